@@ -3,110 +3,386 @@ use crate::error::*;
 use crate::runfile;
 use crate::values::*;
 use crate::evaluator::*;
+use crate::vm::Backend;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+/// Variables are stored in slots in the order they are defined, which never change once assigned,
+/// and found by name through `slots`.
+#[derive(Debug, Clone, Default)]
 pub struct Scope {
+    slots: HashMap<String, usize>,
     vars: Vec<(String, V)>,
-    args: Vec<V>
+    args: Vec<V>,
+    /// The doc comments of the words defined here.
+    docs: HashMap<String, String>
 }
 impl Scope {
-    pub fn new() -> Self { Self { vars: vec![], args: vec![] } }
+    pub fn new() -> Self { Self { slots: HashMap::new(), vars: vec![], args: vec![], docs: HashMap::new() } }
+    #[allow(clippy::result_unit_err)]
     pub fn var(&mut self, word: &String, value: &V) -> Result<(), ()> {
-        for (var, _) in self.vars.iter() {
-            if word == var { return Err(()) }
-        }
+        if self.slots.contains_key(word) { return Err(()) }
+        self.slots.insert(word.clone(), self.vars.len());
         self.vars.push((word.clone(), value.clone()));
         Ok(())
     }
+    #[allow(clippy::result_unit_err)]
     pub fn set(&mut self, word: &String, value: &V) -> Result<(), ()> {
-        for (var, v) in self.vars.iter_mut() {
-            if word == var { *v = value.clone(); return Ok(()) }
-        }
-        Err(())
+        let Some(slot) = self.slots.get(word) else { return Err(()) };
+        self.vars[*slot].1 = value.clone();
+        Ok(())
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.vars.iter().map(|(word, value)| (word, value))
     }
     pub fn get(&self, word: &String) -> Option<&V> {
-        for i in 0..self.args.len() {
-            if word == &i.to_string() { return Some(&self.args[i]) }
+        // only a number can name an argument
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            if let Some(arg) = word.parse::<usize>().ok().and_then(|idx| self.args.get(idx)) { return Some(arg) }
         }
-        for (var, value) in &self.vars {
-            if word == var { return Some(value) }
-        }
-        None
+        self.slots.get(word).map(|slot| &self.vars[*slot].1)
+    }
+    pub fn slot(&self, word: &String) -> Option<usize> {
+        self.slots.get(word).copied()
+    }
+    pub fn get_slot(&self, slot: usize) -> Option<&V> {
+        self.vars.get(slot).map(|(_, value)| value)
+    }
+    /// The variable in `slot`, if it is `word`.
+    pub fn local(&self, word: &String, slot: usize) -> Option<&V> {
+        self.vars.get(slot).filter(|(name, _)| name == word).map(|(_, value)| value)
+    }
+    pub fn arg(&self, idx: usize) -> Option<&V> {
+        self.args.get(idx)
+    }
+    pub fn doc(&self, word: &str) -> Option<&String> {
+        self.docs.get(word)
+    }
+    pub fn document(&mut self, word: &str, doc: &str) {
+        self.docs.insert(word.to_string(), doc.to_string());
     }
 }
 
+/// The scopes a closure was created in: its chain of scopes, innermost last, and the global scope of
+/// its module. Closures hold on to it so they keep seeing the variables around their definition.
 #[derive(Debug, Clone)]
+pub struct Env {
+    pub scopes: Vec<Rc<RefCell<Scope>>>,
+    pub global: Rc<RefCell<Scope>>
+}
+impl Env {
+    /// The value of a variable in these scopes, innermost first, or else in the global scope.
+    pub fn get(&self, word: &String) -> Option<V> {
+        for scope in self.scopes.iter().rev() {
+            let v = scope.borrow().get(word).cloned();
+            if v.is_some() { return v }
+        }
+        self.global.borrow().get(word).cloned()
+    }
+    pub(crate) fn set(&self, word: &String, value: &V) -> Result<(), ()> {
+        for scope in self.scopes.iter().rev() {
+            let res = scope.borrow_mut().set(word, value);
+            if res.is_ok() { return Ok(()) }
+        }
+        Err(())
+    }
+}
+
+/// A call of a closure or function: the word it was called by, or `#closure` if it has none,
+/// and where it was called from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub name: String,
+    pub pos: Position,
+    pub path: String
+}
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}:{}:{}", self.name, self.path, self.pos.0.start + 1, self.pos.1.start + 1)
+    }
+}
+
+/// The maximum call depth of a new context.
+pub static MAX_DEPTH: usize = 1000;
+/// How many bytes of the Rust stack the calls of a new context may take, half of what a thread gets by default.
+pub static MAX_STACK: usize = 1024 * 1024;
+/// How many bytes a string made by `repeat` may have.
+pub static MAX_STRING: usize = 1 << 30;
+
+#[derive(Debug)]
 pub struct Context {
     pub path: String,
-    pub scopes: Vec<Scope>,
-    pub global: Scope,
-    pub trace: Vec<(Position, String)>
+    pub scopes: Vec<Rc<RefCell<Scope>>>,
+    pub callers: Vec<Env>,
+    pub global: Rc<RefCell<Scope>>,
+    pub prelude: Scope,
+    pub modules: HashMap<String, V>,
+    pub importing: Vec<String>,
+    pub search_path: Vec<PathBuf>,
+    /// The text of every file that has been run, so errors can show the lines they point at.
+    pub sources: HashMap<String, String>,
+    /// The doc comments the lexer found right before a `def`, by the file, line and column of the `def`.
+    /// The defined word only gets its doc comment once the `def` succeeds, see [`Context::document`].
+    pub doc_comments: HashMap<(String, usize, usize), String>,
+    pub trace: Vec<(Position, String)>,
+    /// Extra places an error refers to, like the pattern a call did not match.
+    pub notes: Vec<(String, Position, String)>,
+    /// The calls currently being evaluated, outermost first.
+    pub stack: Vec<Frame>,
+    /// How many calls `stack` may hold before a call fails with [`E::StackOverflow`].
+    pub max_depth: usize,
+    /// How many bytes of the Rust stack the calls may take before a call fails with [`E::StackSize`],
+    /// even if `max_depth` is not reached. A host running deep recursion needs a thread with a larger stack
+    /// and a larger `max_stack`.
+    pub max_stack: usize,
+    /// Where on the Rust stack the outermost call started.
+    pub(crate) stack_base: usize,
+    /// The call stack at the point the current error was raised.
+    pub backtrace: Option<Vec<Frame>>,
+    /// How many more nodes may be evaluated before evaluation fails with [`E::OutOfFuel`], `None` for no limit.
+    pub fuel: Option<u64>,
+    pub backend: Backend
 }
 impl Context {
-    pub fn new(path: &String) -> Self { Self { path: path.clone(), scopes: vec![Scope::new()], global: Scope::new(), trace: vec![] } }
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(), scopes: vec![Rc::new(RefCell::new(Scope::new()))], callers: vec![],
+            global: Rc::new(RefCell::new(Scope::new())), prelude: Scope::new(),
+            modules: HashMap::new(), importing: vec![], search_path: vec![],
+            sources: HashMap::new(), doc_comments: HashMap::new(), trace: vec![], notes: vec![],
+            stack: vec![], max_depth: MAX_DEPTH, max_stack: MAX_STACK, stack_base: 0, backtrace: None, fuel: None, backend: Backend::Tree
+        }
+    }
+    /// Finds the file a `load` or `import` in the current file refers to. Relative paths are looked up
+    /// next to the current file first, then in every directory of the search path and at last in the working directory.
+    pub fn resolve(&self, path: &String) -> Option<String> {
+        let file = Path::new(path);
+        if file.is_absolute() {
+            return if file.is_file() { Some(path.clone()) } else { None }
+        }
+        let dir = Path::new(&self.path).parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+        for dir in [dir].iter().chain(self.search_path.iter()) {
+            let candidate = dir.join(file);
+            if candidate.is_file() { return Some(candidate.to_string_lossy().to_string()) }
+        }
+        if file.is_file() { return Some(path.clone()) }
+        None
+    }
+    pub fn env(&self) -> Env {
+        Env { scopes: self.scopes.clone(), global: self.global.clone() }
+    }
+    /// Makes `env` the current environment and returns the one it replaces.
+    pub fn enter(&mut self, env: Env) -> Env {
+        Env {
+            scopes: std::mem::replace(&mut self.scopes, env.scopes),
+            global: std::mem::replace(&mut self.global, env.global)
+        }
+    }
     pub fn push(&mut self) {
-        self.scopes.push(Scope::new());
+        self.scopes.push(Rc::new(RefCell::new(Scope::new())));
     }
-    pub fn pop(&mut self) -> Option<Scope> {
+    pub fn pop(&mut self) -> Option<Rc<RefCell<Scope>>> {
         self.scopes.pop()
     }
-    pub fn args(&mut self, args: &Vec<V>) {
-        self.scopes.last_mut().unwrap().args = args.clone();
+    pub fn args(&mut self, args: &[V]) {
+        self.scopes.last().unwrap().borrow_mut().args = args.to_vec();
     }
     pub fn trace(&mut self, pos: &Position) {
         self.trace.push((pos.clone(), self.path.clone()))
     }
+    /// Takes one step of fuel for every node evaluated at `poses`. If there is not enough left,
+    /// the node it runs out at is traced and the fuel is used up.
+    pub fn burn(&mut self, poses: &[Position]) -> Result<(), E> {
+        let Some(fuel) = self.fuel else { return Ok(()) };
+        if poses.len() as u64 <= fuel {
+            self.fuel = Some(fuel - poses.len() as u64);
+            return Ok(())
+        }
+        self.fuel = Some(0);
+        self.trace(&poses[fuel as usize]);
+        Err(E::OutOfFuel)
+    }
+    pub fn note(&mut self, note: &str, pos: &Position, path: &str) {
+        self.notes.push((note.to_string(), pos.clone(), path.to_string()))
+    }
+    #[allow(clippy::result_unit_err)]
     pub fn var(&mut self, word: &String, value: &V) -> Result<(), ()> {
         for scope in self.scopes.iter() {
-            let v = scope.get(word);
-            if v.is_some() { return Err(()) }
+            let v = scope.borrow().get(word).is_some();
+            if v { return Err(()) }
         }
-        for scope in self.scopes.iter_mut().rev() {
-            let res = scope.var(word, value);
+        for scope in self.scopes.iter().rev() {
+            let res = scope.borrow_mut().var(word, value);
             if res.is_ok() { return Ok(()) }
         }
         Err(())
     }
+    #[allow(clippy::result_unit_err)]
     pub fn set(&mut self, word: &String, value: &V) -> Result<(), ()> {
-        for scope in self.scopes.iter_mut().rev() {
-            let res = scope.set(word, value);
+        for scope in self.scopes.iter().rev() {
+            let res = scope.borrow_mut().set(word, value);
             if res.is_ok() { return Ok(()) }
         }
         Err(())
     }
+    #[allow(clippy::result_unit_err)]
     pub fn def(&mut self, word: &String, value: &V) -> Result<(), ()> {
-        self.global.var(word, value)
+        if self.prelude.get(word).is_some() { return Err(()) }
+        self.global.borrow_mut().var(word, value)
     }
-    pub fn get(&self, word: &String) -> Option<&V> {
+    pub fn get(&self, word: &String) -> Option<V> {
         for scope in self.scopes.iter().rev() {
-            let v = scope.get(word);
-            if v.is_some() { return Some(v.unwrap()) }
+            let v = scope.borrow().get(word).cloned();
+            if v.is_some() { return v }
         }
-        self.global.get(word)
+        let v = self.global.borrow().get(word).cloned();
+        if v.is_some() { return v }
+        self.prelude.get(word).cloned()
     }
-    pub fn is_global(&self, word: &String) -> bool {
-        self.global.get(word).is_some()
+    /// The value of a word the resolver found in `slot` of the prelude, see [`crate::resolver`].
+    pub fn get_prelude(&self, slot: usize) -> Option<V> {
+        self.prelude.get_slot(slot).cloned()
+    }
+    /// The value of a variable the resolver found in `slot` of the scope `depth` scopes out, see [`crate::resolver`].
+    /// If the variable is not there, because it was defined in a way the resolver could not see, it is looked up by name.
+    pub fn get_local(&self, word: &String, depth: usize, slot: usize) -> Option<V> {
+        if let Some(scope) = self.scopes.len().checked_sub(depth + 1).map(|idx| self.scopes[idx].borrow()) {
+            if let Some(value) = scope.local(word, slot) { return Some(value.clone()) }
+        }
+        self.get(word)
+    }
+    /// The argument `idx` of the innermost call that has one.
+    pub fn arg(&self, idx: i64) -> Option<V> {
+        let idx = usize::try_from(idx).ok()?;
+        for scope in self.scopes.iter().rev() {
+            let v = scope.borrow().arg(idx).cloned();
+            if v.is_some() { return v }
+        }
+        self.global.borrow().arg(idx).cloned()
+    }
+    /// Like `get` for the word of an address, which is looked up in the scopes `env` the address was made in,
+    /// so an address passed to a function (e.g. `inc!`) still refers to the variable of the caller.
+    /// An address without scopes, cast from another value, is looked up here.
+    pub fn get_addr(&self, word: &String, env: &Option<Env>) -> Option<V> {
+        match env {
+            Some(env) => env.get(word).or_else(|| self.prelude.get(word).cloned()),
+            None => self.get(word)
+        }
+    }
+    /// Like `set` for the word of an address, see `get_addr`.
+    pub(crate) fn set_addr(&mut self, word: &String, env: &Option<Env>, value: &V) -> Result<(), ()> {
+        match env {
+            Some(env) => env.set(word, value),
+            None => self.set(word, value)
+        }
+    }
+    /// Gives the global `word` the doc comment written right before the `def` at `pos`, if there is one.
+    pub fn document(&mut self, word: &str, pos: &Position) {
+        let key = (self.path.clone(), pos.0.start, pos.1.start);
+        if let Some(doc) = self.doc_comments.remove(&key) { self.global.borrow_mut().document(word, &doc) }
+    }
+    /// The doc comment of a global word or a word of the prelude, where `a.b` is the word `b` of the module `a`.
+    pub fn doc(&self, word: &str) -> Option<String> {
+        let doc = self.global.borrow().doc(word).or_else(|| self.prelude.doc(word)).cloned();
+        if doc.is_some() { return doc }
+        let (module, word) = word.rsplit_once('.')?;
+        let V::Module(_, global) = self::word(&module.to_string(), self) else { return None };
+        let doc = global.borrow().doc(word).cloned();
+        doc
+    }
+    /// The words `doc` finds a doc comment for without going into modules, sorted.
+    pub fn documented(&self) -> Vec<String> {
+        let mut words: Vec<String> = self.global.borrow().docs.keys().chain(self.prelude.docs.keys()).cloned().collect();
+        words.sort();
+        words.dedup();
+        words
+    }
+    /// Whether `word` is defined globally or in the prelude, where `env` is the scopes of an address like in `get_addr`.
+    pub fn is_global(&self, word: &String, env: &Option<Env>) -> bool {
+        let global = env.as_ref().map(|env| &env.global).unwrap_or(&self.global);
+        global.borrow().get(word).is_some() || self.prelude.get(word).is_some()
+    }
+    /// Moves everything defined globally so far into the prelude, which every module starts with.
+    pub fn commit_prelude(&mut self) {
+        let global = std::mem::replace(&mut self.global, Rc::new(RefCell::new(Scope::new())));
+        for (word, value) in global.borrow().iter() {
+            let _ = self.prelude.var(word, value);
+        }
+        self.prelude.docs.extend(global.borrow().docs.clone());
+    }
+    /// Defines a global native function backed by a Rust closure, which may capture host state.
+    /// With a `pattern` the argument types are checked before `f` is called, `None` accepts anything.
+    pub fn register_native<F>(&mut self, word: &str, pattern: Option<Vec<Type>>, f: F) -> Result<(), E>
+    where F: Fn(Vec<V>, &mut Context, &Position, &[&Position]) -> Result<(V, R), E> + 'static {
+        let params = match pattern {
+            Some(types) => V::Pattern(types),
+            None => V::Null
+        };
+        let res = self.prelude.var(&word.to_string(), &V::NativFunction(Box::new(params), Rc::new(f)));
+        if res.is_err() { return Err(E::AlreadyDefined(word.to_string())) }
+        Ok(())
+    }
+}
+
+impl Drop for Context {
+    /// Closures hold on to the scopes they were created in, which are often the scopes they are stored in,
+    /// so every scope reachable from here is emptied to break those cycles.
+    fn drop(&mut self) {
+        let mut scopes: Vec<Rc<RefCell<Scope>>> = self.scopes.drain(..).collect();
+        scopes.push(self.global.clone());
+        for env in self.callers.drain(..) {
+            scopes.extend(env.scopes);
+            scopes.push(env.global);
+        }
+        let mut values: Vec<V> = self.modules.drain().map(|(_, module)| module).collect();
+        values.extend(std::mem::take(&mut self.prelude).vars.into_iter().map(|(_, value)| value));
+        loop {
+            if let Some(value) = values.pop() {
+                match value {
+                    V::Closure(_, _, env) | V::Addr(_, Some(env)) => {
+                        scopes.extend(env.scopes);
+                        scopes.push(env.global);
+                    }
+                    V::Module(_, global) => scopes.push(global),
+                    V::Vector(items, _) => values.extend(items),
+                    V::Map(map) => values.extend(map.into_values()),
+                    V::Function(_, value, _) => values.push(*value),
+                    V::Error(e, _, _) => if let E::Thrown(value) = *e { values.push(value) },
+                    _ => {}
+                }
+            } else if let Some(scope) = scopes.pop() {
+                let scope = std::mem::take(&mut *scope.borrow_mut());
+                values.extend(scope.vars.into_iter().map(|(_, value)| value));
+                values.extend(scope.args);
+            } else {
+                break
+            }
+        }
     }
 }
 
-pub fn _def(args: Vec<V>, context: &mut Context, pos: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
+pub fn _def(args: Vec<V>, context: &mut Context, pos: &Position, poses: &[&Position]) -> Result<(V, R), E> {
     let addr = &args[0];
     let value = args.get(1).unwrap_or_else(|| &V::Null);
-    if let V::Addr(word) = addr {
+    if let V::Addr(word, _) = addr {
         let res = context.def(word, value);
         if res.is_err() {
-            context.trace(&poses[0]);
+            context.trace(poses[0]);
             return Err(E::AlreadyDefined(word.clone()))
         }
+        context.document(word, pos);
         return Ok((V::Null, R::None))
     }
     context.trace(pos);
     Err(E::ExpectedType { typ: Type::Addr, recv_typ: addr.typ() })
 }
-pub fn _var(args: Vec<V>, context: &mut Context, pos: &Position, _: &Vec<&Position>) -> Result<(V, R), E> {
+pub fn _var(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
     let addr = &args[0];
     let value = args.get(1).unwrap_or_else(|| &V::Null);
-    if let V::Addr(word) = addr {
+    if let V::Addr(word, _) = addr {
         let res = context.var(word, value);
         if res.is_err() {
             context.trace(pos);
@@ -117,15 +393,15 @@ pub fn _var(args: Vec<V>, context: &mut Context, pos: &Position, _: &Vec<&Positi
     context.trace(pos);
     Err(E::ExpectedType { typ: Type::Addr, recv_typ: addr.typ() })
 }
-pub fn _set(args: Vec<V>, context: &mut Context, pos: &Position, _: &Vec<&Position>) -> Result<(V, R), E> {
+pub fn _set(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
     let addr = &args[0];
     let value = args.get(1).unwrap_or_else(|| &V::Null);
-    if let V::Addr(word) = addr {
-        if context.is_global(word) {
+    if let V::Addr(word, env) = addr {
+        if context.is_global(word, env) {
             context.trace(pos);
             return Err(E::Immutable(word.clone()))
         }
-        let res = context.set(word, value);
+        let res = context.set_addr(word, env, value);
         if res.is_err() {
             context.trace(pos);
             return Err(E::NotDefined(word.clone()))
@@ -135,141 +411,178 @@ pub fn _set(args: Vec<V>, context: &mut Context, pos: &Position, _: &Vec<&Positi
     context.trace(pos);
     Err(E::ExpectedType { typ: Type::Addr, recv_typ: addr.typ() })
 }
-pub fn _get(args: Vec<V>, context: &mut Context, pos: &Position, _: &Vec<&Position>) -> Result<(V, R), E> {
+pub fn _get(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
     let addr = &args[0];
-    if let V::Addr(word) = addr {
-        let v = context.get(word);
-        if v.is_some() {
-            return Ok((v.unwrap().clone(), R::None))
-        }
-        return Ok((V::Null, R::None))
+    if let V::Addr(word, env) = addr {
+        return Ok((context.get_addr(word, env).unwrap_or(V::Null), R::None))
     }
     context.trace(pos);
     Err(E::ExpectedType { typ: Type::Addr, recv_typ: addr.typ() })
 }
-pub fn _if(args: Vec<V>, context: &mut Context, _: &Position, _: &Vec<&Position>) -> Result<(V, R), E> {
-    let cond = &args[0];
-    let case = args.get(1).unwrap_or_else(|| &V::Null);
-    let else_case = args.get(2).unwrap_or_else(|| &V::Null);
-    if cond == &V::Bool(true) {
-        if let V::Closure(n, cpath) = case {
-            let path = context.path.clone();
-            context.path = cpath.clone();
-            let res = get(n, context);
-            context.path = path;
-        }
-        return Ok((case.clone(), R::None))
-    } else if else_case != &V::Null {
-        if let V::Closure(n, cpath) = else_case {
-            let path = context.path.clone();
-            context.path = cpath.clone();
-            let res = get(n, context);
-            context.path = path;
-        }
-        return Ok((else_case.clone(), R::None))
-    }
-    return Ok((V::Null, R::None))
-}
-pub fn _while(args: Vec<V>, context: &mut Context, _: &Position, _: &Vec<&Position>) -> Result<(V, R), E> {
+/// The case `if` takes for its arguments: the second if the condition is true, else the third.
+pub fn branch(args: &[V]) -> &V {
+    let case = if args[0] == V::Bool(true) { args.get(1) } else { args.get(2) };
+    case.unwrap_or(&V::Null)
+}
+pub fn _if(args: Vec<V>, context: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Closure(n, cpath, env) = branch(&args) {
+        return closure(n, cpath, env, None, context)
+    }
+    Ok((branch(&args).clone(), R::None))
+}
+pub fn _while(args: Vec<V>, context: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
     let mut cond = Type::Bool.cast(&args[0]);
     let case = args.get(1).unwrap_or_else(|| &V::Null);
-    if let V::Closure(n, cpath) = &args[0] {
-        let path = context.path.clone();
-        context.path = cpath.clone();
-        let (value, _) = get(n, context)?;
-        context.path = path;
+    if let V::Closure(n, cpath, env) = &args[0] {
+        let (value, _) = closure(n, cpath, env, None, context)?;
         cond = Type::Bool.cast(&value);
     }
     while cond == V::Bool(true) {
-        if let V::Closure(n, cpath) = case {
-            let path = context.path.clone();
-            context.path = cpath.clone();
-            let (value, ret) = get(n, context)?;
-            context.path = path;
-            if ret == R::Return { return Ok((value, ret)) }
-            if ret == R::Break { return Ok((value, R::None)) }
-        }
-        if let V::Closure(n, cpath) = &args[0] {
-            let path = context.path.clone();
-            context.path = cpath.clone();
-            let (value, _) = get(n, context)?;
-            context.path = path;
+        if let V::Closure(n, cpath, env) = case {
+            let (value, ret) = closure(n, cpath, env, None, context)?;
+            match ret {
+                R::Return => return Ok((value, ret)),
+                R::Break => return Ok((value, R::None)),
+                R::Continue | R::None => {}
+            }
+        }
+        if let V::Closure(n, cpath, env) = &args[0] {
+            let (value, _) = closure(n, cpath, env, None, context)?;
             cond = Type::Bool.cast(&value);
         } else {
             cond = Type::Bool.cast(&args[0]);
         }
     }
-    return Ok((V::Null, R::None))
+    Ok((V::Null, R::None))
+}
+pub fn _for(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Addr(word, _) = &args[0] else {
+        context.trace(pos);
+        return Err(E::ExpectedType { typ: Type::Addr, recv_typ: args[0].typ() })
+    };
+    // the loop variable lives in its own scope which is visible to the condition, step and body closures
+    let mut scope = Scope::new();
+    let _ = scope.var(word, &args[1]);
+    let scope = Rc::new(RefCell::new(scope));
+    let run = |value: &V, context: &mut Context| -> Result<(V, R), E> {
+        if let V::Closure(n, cpath, env) = value {
+            let mut env = env.clone();
+            env.scopes.push(scope.clone());
+            return closure(n, cpath, &env, None, context)
+        }
+        Ok((value.clone(), R::None))
+    };
+    loop {
+        let (cond, _) = run(&args[2], context)?;
+        if Type::Bool.cast(&cond) != V::Bool(true) { break }
+        let (value, ret) = run(&args[4], context)?;
+        match ret {
+            R::Return => return Ok((value, ret)),
+            R::Break => return Ok((value, R::None)),
+            R::Continue | R::None => {}
+        }
+        run(&args[3], context)?;
+    }
+    Ok((V::Null, R::None))
 }
-pub fn _print(args: Vec<V>, _: &mut Context, _: &Position, _: &Vec<&Position>) -> Result<(V, R), E> {
+pub fn _return(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((args.first().cloned().unwrap_or(V::Null), R::Return))
+}
+pub fn _break(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((args.first().cloned().unwrap_or(V::Null), R::Break))
+}
+pub fn _continue(_: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Null, R::Continue))
+}
+pub fn _print(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
     for i in 0..args.len() {
         print!("{}", &args[i]);
         if i < args.len() - 1 { print!(" "); }
     }
-    if args.len() > 0 { println!(); }
+    if !args.is_empty() { println!(); }
     Ok((V::Null, R::None))
 }
-pub fn _add(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
+/// Applies `op` from left to right over all arguments, like `(op (op a b) c)`.
+fn fold(args: &[V], op: fn(&V, &V) -> Result<V, E>, context: &mut Context, poses: &[&Position]) -> Result<(V, R), E> {
+    if args.is_empty() { return Ok((V::Null, R::None)) }
     let mut sum = args[0].clone();
     for i in 1..args.len() {
-        let v = sum.add(&args[i]);
-        if v.is_none() {
-            context.trace(poses[i]);
-            return Err(E::BinaryOperation { type1: sum.typ(), type2: args[i].typ() })
+        match op(&sum, &args[i]) {
+            Ok(v) => sum = v,
+            Err(e) => {
+                context.trace(poses[i]);
+                return Err(e)
+            }
         }
-        sum = v.unwrap();
     }
     Ok((sum, R::None))
 }
-pub fn _sub(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
+pub fn _add(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    fold(&args, V::add, context, poses)
+}
+pub fn _sub(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
     if args.len() == 1 {
-        let number = V::Int(0).sub(&args[0]);
-        if number.is_none() {
-            context.trace(poses[0]);
-            return Err(E::UnaryOperation(args[0].typ()))
+        return match V::Int(0).sub(&args[0]) {
+            Ok(number) => Ok((number, R::None)),
+            Err(E::BinaryOperation { .. }) => {
+                context.trace(poses[0]);
+                Err(E::UnaryOperation(args[0].typ()))
+            }
+            Err(e) => {
+                context.trace(poses[0]);
+                Err(e)
+            }
         }
-        return Ok((number.unwrap(), R::None))
     }
-    let mut sum = args[0].clone();
-    for i in 1..args.len() {
-        let v = sum.sub(&args[i]);
-        if v.is_none() {
-            context.trace(poses[i]);
-            return Err(E::BinaryOperation { type1: sum.typ(), type2: args[i].typ() })
-        }
-        sum = v.unwrap();
-    }
-    Ok((sum, R::None))
+    fold(&args, V::sub, context, poses)
 }
-pub fn _mul(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
-    let mut sum = args[0].clone();
-    for i in 1..args.len() {
-        let v = sum.mul(&args[i]);
-        if v.is_none() {
-            context.trace(poses[i]);
-            return Err(E::BinaryOperation { type1: sum.typ(), type2: args[i].typ() })
-        }
-        sum = v.unwrap();
+pub fn _mul(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    fold(&args, V::mul, context, poses)
+}
+pub fn _div(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    fold(&args, V::div, context, poses)
+}
+pub fn _int_div(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    fold(&args, V::int_div, context, poses)
+}
+pub fn _mod(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    fold(&args, V::rem, context, poses)
+}
+pub fn _pow(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    fold(&args, V::pow, context, poses)
+}
+pub fn _abs(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    args[0].abs().map(|v| (v, R::None)).inspect_err(|_| context.trace(poses[0]))
+}
+fn int(v: &V) -> i64 {
+    if let V::Int(v) = v { *v } else { 0 }
+}
+pub fn _bit_and(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Int(int(&args[0]) & int(&args[1])), R::None))
+}
+pub fn _bit_or(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Int(int(&args[0]) | int(&args[1])), R::None))
+}
+pub fn _bit_xor(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Int(int(&args[0]) ^ int(&args[1])), R::None))
+}
+pub fn _bit_not(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Int(!int(&args[0])), R::None))
+}
+/// Shifts the bits of an int, failing for shifts by less than 0 or more than 63 bits.
+pub fn _shl(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    match u32::try_from(int(&args[1])).ok().and_then(|shift| int(&args[0]).checked_shl(shift)) {
+        Some(v) => Ok((V::Int(v), R::None)),
+        None => { context.trace(poses[1]); Err(E::Overflow("shl".to_string())) }
     }
-    Ok((sum, R::None))
 }
-pub fn _div(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
-    let mut sum = args[0].clone();
-    for i in 1..args.len() {
-        let v = sum.div(&args[i]);
-        if v.is_none() {
-            context.trace(poses[i]);
-            return Err(E::BinaryOperation { type1: sum.typ(), type2: args[i].typ() })
-        }
-        sum = v.unwrap();
+pub fn _shr(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    match u32::try_from(int(&args[1])).ok().and_then(|shift| int(&args[0]).checked_shr(shift)) {
+        Some(v) => Ok((V::Int(v), R::None)),
+        None => { context.trace(poses[1]); Err(E::Overflow("shr".to_string())) }
     }
-    Ok((sum, R::None))
 }
-pub fn _eq(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
+pub fn _eq(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
     if args.len() <= 1 { return Ok((V::Bool(false), R::None)) }
     for i in 0..args.len() {
         for j in 0..args.len() {
@@ -278,8 +591,8 @@ pub fn _eq(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Posit
     }
     Ok((V::Bool(true), R::None))
 }
-pub fn _lt(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
+pub fn _lt(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    if args.is_empty() { return Ok((V::Null, R::None)) }
     for i in 0..args.len()-1 {
         let v = args[i].lt(&args[i+1]);
         if v.is_none() {
@@ -290,8 +603,8 @@ pub fn _lt(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Posit
     }
     Ok((V::Bool(true), R::None))
 }
-pub fn _gt(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
+pub fn _gt(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    if args.is_empty() { return Ok((V::Null, R::None)) }
     for i in 0..args.len()-1 {
         let v = args[i].gt(&args[i+1]);
         if v.is_none() {
@@ -302,98 +615,538 @@ pub fn _gt(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Posit
     }
     Ok((V::Bool(true), R::None))
 }
-pub fn _union(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Type(Type::Union(vec![Type::Any])), R::None)) }
+pub fn _union(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    if args.is_empty() { return Ok((V::Type(Type::Union(vec![Type::Any])), R::None)) }
     let mut types: Vec<Type> = vec![];
     for i in 0..args.len() {
         if let V::Type(typ) = &args[i] {
             types.push(typ.clone());
         } else {
-            context.trace(&poses[i]);
+            context.trace(poses[i]);
             return Err(E::ExpectedType { typ: Type::Type, recv_typ: args[i].typ() })
         }
     }
-    return Ok((V::Type(Type::Union(types)), R::None))
+    Ok((V::Type(Type::Union(types)), R::None))
 }
-pub fn _exclude(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Type(Type::Union(vec![Type::Any])), R::None)) }
+pub fn _exclude(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    if args.is_empty() { return Ok((V::Type(Type::Union(vec![Type::Any])), R::None)) }
     let mut types: Vec<Type> = vec![];
     for i in 0..args.len() {
         if let V::Type(typ) = &args[i] {
             types.push(typ.clone());
         } else {
-            context.trace(&poses[i]);
+            context.trace(poses[i]);
             return Err(E::ExpectedType { typ: Type::Type, recv_typ: args[i].typ() })
         }
     }
-    return Ok((V::Type(Type::Exclusion(types)), R::None))
+    Ok((V::Type(Type::Exclusion(types)), R::None))
+}
+pub fn _map_get(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let (V::Map(map), Some(key)) = (&args[0], args[1].key()) {
+        return Ok((map.get(&key).cloned().unwrap_or(V::Null), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _map_insert(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let (V::Map(map), Some(key)) = (&args[0], args[1].key()) {
+        let mut map = map.clone();
+        map.insert(key, args[2].clone());
+        return Ok((V::Map(map), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _map_remove(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let (V::Map(map), Some(key)) = (&args[0], args[1].key()) {
+        let mut map = map.clone();
+        map.remove(&key);
+        return Ok((V::Map(map), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _keys(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Map(map) = &args[0] {
+        return Ok((V::vector(map.keys().map(|k| V::String(k.clone())).collect()), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _values(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Map(map) = &args[0] {
+        return Ok((V::vector(map.values().cloned().collect()), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _has(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let (V::Map(map), Some(key)) = (&args[0], args[1].key()) {
+        return Ok((V::Bool(map.contains_key(&key)), R::None))
+    }
+    Ok((V::Bool(false), R::None))
+}
+pub fn _len(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    match &args[0] {
+        V::String(string) => Ok((V::Int(string.chars().count() as i64), R::None)),
+        V::Vector(values, _) => Ok((V::Int(values.len() as i64), R::None)),
+        V::Map(map) => Ok((V::Int(map.len() as i64), R::None)),
+        _ => Ok((V::Null, R::None))
+    }
+}
+pub fn _index(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Int(idx) = &args[1] else { return Ok((V::Null, R::None)) };
+    if *idx < 0 { return Ok((V::Null, R::None)) }
+    match &args[0] {
+        V::Vector(values, _) => Ok((values.get(*idx as usize).cloned().unwrap_or(V::Null), R::None)),
+        V::String(string) => Ok((string.chars().nth(*idx as usize).map(|c| V::String(c.to_string())).unwrap_or(V::Null), R::None)),
+        _ => Ok((V::Null, R::None))
+    }
+}
+pub fn _push(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Vector(values, _) = &args[0] {
+        let mut values = values.clone();
+        values.extend(args[1..].iter().cloned());
+        return Ok((V::vector(values), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _pop(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Vector(values, _) = &args[0] {
+        let mut values = values.clone();
+        values.pop();
+        return Ok((V::vector(values), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _slice(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Vector(values, _) = &args[0] {
+        let len = values.len() as i64;
+        let start = if let V::Int(start) = &args[1] { (*start).clamp(0, len) } else { 0 };
+        let end = if let Some(V::Int(end)) = args.get(2) { (*end).clamp(start, len) } else { len };
+        return Ok((V::vector(values[start as usize..end as usize].to_vec()), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _concat(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    let mut values: Vec<V> = vec![];
+    for i in 0..args.len() {
+        if let V::Vector(v, _) = &args[i] {
+            values.extend(v.iter().cloned());
+        } else {
+            context.trace(poses[i]);
+            return Err(E::ExpectedType { typ: Type::vector(), recv_typ: args[i].typ() })
+        }
+    }
+    Ok((V::vector(values), R::None))
+}
+pub fn _reverse(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Vector(values, typ) = &args[0] {
+        let mut values = values.clone();
+        values.reverse();
+        return Ok((V::Vector(values, typ.clone()), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _sort(args: Vec<V>, context: &mut Context, pos: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    let V::Vector(values, typ) = &args[0] else { return Ok((V::Null, R::None)) };
+    let func = args.get(1).unwrap_or(&V::Null);
+    // merge sort since comparing two values can fail or call back into funx
+    fn sort(values: Vec<V>, func: &V, context: &mut Context, pos: &Position) -> Result<Vec<V>, E> {
+        if values.len() <= 1 { return Ok(values) }
+        let mut right = values;
+        let left = right.drain(..right.len() / 2).collect();
+        let left = sort(left, func, context, pos)?;
+        let right = sort(right, func, context, pos)?;
+        let mut sorted = Vec::with_capacity(left.len() + right.len());
+        let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+        while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+            let less = if func == &V::Null {
+                match (l, r) {
+                    (V::String(v1), V::String(v2)) => V::Bool(v2 < v1),
+                    _ => r.lt(l).ok_or_else(|| E::BinaryOperation { type1: r.typ(), type2: l.typ() })?
+                }
+            } else {
+                call(func, vec![r.clone(), l.clone()], pos, context)?.0
+            };
+            if less == V::Bool(true) { sorted.push(right.next().unwrap()) } else { sorted.push(left.next().unwrap()) }
+        }
+        sorted.extend(left);
+        sorted.extend(right);
+        Ok(sorted)
+    }
+    let res = sort(values.clone(), func, context, pos);
+    if res.is_err() && func == &V::Null { context.trace(poses[0]); }
+    Ok((V::Vector(res?, typ.clone()), R::None))
+}
+pub fn _map_vec(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Vector(values, _) = &args[0] else { return Ok((V::Null, R::None)) };
+    let mut mapped: Vec<V> = vec![];
+    for value in values {
+        let (value, ret) = call(&args[1], vec![value.clone()], pos, context)?;
+        match ret {
+            R::Return => return Ok((value, ret)),
+            R::Break => break,
+            R::Continue => {}
+            R::None => mapped.push(value)
+        }
+    }
+    Ok((V::vector(mapped), R::None))
+}
+pub fn _filter(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Vector(values, _) = &args[0] else { return Ok((V::Null, R::None)) };
+    let mut filtered: Vec<V> = vec![];
+    for value in values {
+        let (keep, ret) = call(&args[1], vec![value.clone()], pos, context)?;
+        match ret {
+            R::Return => return Ok((keep, ret)),
+            R::Break => break,
+            R::Continue => {}
+            R::None => if keep == V::Bool(true) { filtered.push(value.clone()); }
+        }
+    }
+    Ok((V::vector(filtered), R::None))
+}
+pub fn _reduce(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Vector(values, _) = &args[0] else { return Ok((V::Null, R::None)) };
+    let mut acc = args[1].clone();
+    for value in values {
+        let (value, ret) = call(&args[2], vec![acc.clone(), value.clone()], pos, context)?;
+        match ret {
+            R::Return => return Ok((value, ret)),
+            R::Break => break,
+            R::Continue => {}
+            R::None => acc = value
+        }
+    }
+    Ok((acc, R::None))
+}
+pub fn _each(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Vector(values, _) = &args[0] else { return Ok((V::Null, R::None)) };
+    for value in values {
+        let (value, ret) = call(&args[1], vec![value.clone()], pos, context)?;
+        match ret {
+            R::Return => return Ok((value, ret)),
+            R::Break => return Ok((value, R::None)),
+            R::Continue | R::None => {}
+        }
+    }
+    Ok((V::Null, R::None))
+}
+fn string(value: &V) -> &str {
+    if let V::String(string) = value { return string }
+    ""
+}
+pub fn _substr(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let chars: Vec<char> = string(&args[0]).chars().collect();
+    let len = chars.len() as i64;
+    let start = if let V::Int(start) = &args[1] { (*start).clamp(0, len) } else { 0 };
+    let end = if let Some(V::Int(end)) = args.get(2) { (*end).clamp(start, len) } else { len };
+    Ok((V::String(chars[start as usize..end as usize].iter().collect()), R::None))
+}
+pub fn _split(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let (text, sep) = (string(&args[0]), string(&args[1]));
+    let parts: Vec<V> = if sep.is_empty() {
+        text.chars().map(|c| V::String(c.to_string())).collect()
+    } else {
+        text.split(sep).map(|part| V::String(part.to_string())).collect()
+    };
+    Ok((V::Vector(parts, Type::Union(vec![Type::String])), R::None))
+}
+pub fn _join(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let V::Vector(values, _) = &args[0] else { return Ok((V::Null, R::None)) };
+    let sep = if let Some(V::String(sep)) = args.get(1) { sep.as_str() } else { "" };
+    Ok((V::String(values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(sep)), R::None))
+}
+pub fn _trim(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::String(string(&args[0]).trim().to_string()), R::None))
+}
+pub fn _upper(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::String(string(&args[0]).to_uppercase()), R::None))
+}
+pub fn _lower(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::String(string(&args[0]).to_lowercase()), R::None))
+}
+pub fn _contains(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Bool(string(&args[0]).contains(string(&args[1]))), R::None))
+}
+pub fn _starts_with(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Bool(string(&args[0]).starts_with(string(&args[1]))), R::None))
+}
+pub fn _ends_with(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::Bool(string(&args[0]).ends_with(string(&args[1]))), R::None))
+}
+pub fn _replace(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    Ok((V::String(string(&args[0]).replace(string(&args[1]), string(&args[2]))), R::None))
+}
+pub fn _find(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let text = string(&args[0]);
+    match text.find(string(&args[1])) {
+        Some(idx) => Ok((V::Int(text[..idx].chars().count() as i64), R::None)),
+        None => Ok((V::Null, R::None))
+    }
+}
+/// Repeats the string, failing if the result would be longer than [`MAX_STRING`] bytes.
+pub fn _repeat(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    let V::Int(times) = &args[1] else { return Ok((V::Null, R::None)) };
+    let text = string(&args[0]);
+    let times = usize::try_from(*times).unwrap_or(0);
+    match text.len().checked_mul(times) {
+        Some(len) if len <= MAX_STRING => Ok((V::String(text.repeat(times)), R::None)),
+        _ => { context.trace(poses[1]); Err(E::Overflow("repeat".to_string())) }
+    }
+}
+/// Formats the string by replacing `{}` with the next argument, `{N}` with the N-th argument and
+/// `{word}` with the value of the variable `word`. `{{` and `}}` stand for single braces.
+pub fn _format(args: Vec<V>, context: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let mut chars = string(&args[0]).chars().peekable();
+    let mut formatted = String::new();
+    let mut next = 1;
+    while let Some(c) = chars.next() {
+        if c == '}' && chars.peek() == Some(&'}') { chars.next(); }
+        if c != '{' { formatted.push(c); continue }
+        if chars.peek() == Some(&'{') { chars.next(); formatted.push(c); continue }
+        let mut name = String::new();
+        for c in chars.by_ref() {
+            if c == '}' { break }
+            name.push(c);
+        }
+        let value = if name.is_empty() {
+            next += 1;
+            args.get(next - 1).cloned()
+        } else if let Ok(idx) = name.parse::<usize>() {
+            args.get(idx + 1).cloned()
+        } else {
+            context.get(&name)
+        };
+        formatted.push_str(&value.unwrap_or(V::Null).to_string());
+    }
+    Ok((V::String(formatted), R::None))
+}
+/// Calls the first argument and, if it fails, passes the error as an error value to the handler.
+/// Without a handler the error value itself is returned.
+pub fn _try(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let (path, env, callers, trace) = (context.path.clone(), context.env(), context.callers.len(), context.trace.len());
+    let notes = context.notes.len();
+    let res = call(&args[0], vec![], pos, context);
+    let Err(e) = res else { return res };
+    // running out of fuel stops the whole evaluation
+    if e == E::OutOfFuel { return Err(e) }
+    let trace = context.trace.split_off(trace);
+    context.notes.truncate(notes);
+    let backtrace = context.backtrace.take().unwrap_or_default();
+    context.path = path;
+    context.enter(env);
+    context.callers.truncate(callers);
+    let error = V::Error(Box::new(e), trace, backtrace);
+    match args.get(1) {
+        Some(handler) if handler.typ() == Type::callable() => call(handler, vec![error], pos, context),
+        _ => Ok((error, R::None))
+    }
+}
+pub fn _throw(args: Vec<V>, context: &mut Context, pos: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let Some(V::Error(e, trace, backtrace)) = args.first() {
+        context.trace.extend(trace.iter().cloned());
+        if !backtrace.is_empty() { context.backtrace = Some(backtrace.clone()) }
+        return Err(e.as_ref().clone())
+    }
+    context.trace(pos);
+    Err(E::Thrown(args.first().cloned().unwrap_or(V::Null)))
+}
+pub fn _error_message(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Error(e, _, _) = &args[0] { return Ok((V::String(e.message()), R::None)) }
+    Ok((V::Null, R::None))
+}
+pub fn _error_kind(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Error(e, _, _) = &args[0] { return Ok((V::String(e.kind().to_string()), R::None)) }
+    Ok((V::Null, R::None))
+}
+pub fn _error_code(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Error(e, _, _) = &args[0] { return Ok((V::String(e.code().to_string()), R::None)) }
+    Ok((V::Null, R::None))
+}
+pub fn _error_value(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Error(e, _, _) = &args[0] {
+        if let E::Thrown(value) = e.as_ref() { return Ok((value.clone(), R::None)) }
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _error_trace(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Error(_, trace, _) = &args[0] {
+        let trace = trace.iter().map(|(pos, path)| V::String(format!("{}:{}:{}", path, pos.0.start + 1, pos.1.start + 1))).collect();
+        return Ok((V::vector(trace), R::None))
+    }
+    Ok((V::Null, R::None))
 }
-pub fn _load(args: Vec<V>, context: &mut Context, _: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
-    if args.len() == 0 { return Ok((V::Null, R::None)) }
+pub fn _error_stack(args: Vec<V>, _: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    if let V::Error(_, _, backtrace) = &args[0] {
+        let frames = backtrace.iter().rev().map(|frame| V::String(frame.to_string())).collect();
+        return Ok((V::vector(frames), R::None))
+    }
+    Ok((V::Null, R::None))
+}
+pub fn _doc(args: Vec<V>, context: &mut Context, _: &Position, _: &[&Position]) -> Result<(V, R), E> {
+    let word = match &args[0] { V::Addr(word, _) | V::String(word) => word, _ => return Ok((V::Null, R::None)) };
+    Ok((context.doc(word).map(V::String).unwrap_or(V::Null), R::None))
+}
+pub fn _load(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    if args.is_empty() { return Ok((V::Null, R::None)) }
     if let V::String(path) = &args[0] {
+        let Some(path) = context.resolve(path) else {
+            context.trace(poses[0]);
+            return Err(E::FileNotFound(path.clone()))
+        };
         let _path = context.path.clone();
         context.path = path.clone();
-        runfile(path, context)?;
+        runfile(&path, context)?;
         context.path = _path;
         return Ok((V::Null, R::None))
     }
-    context.trace(&poses[0]);
+    context.trace(poses[0]);
     Err(E::ExpectedType { typ: Type::String, recv_typ: args[0].typ() })
 }
-pub fn _assert(args: Vec<V>, context: &mut Context, pos: &Position, poses: &Vec<&Position>) -> Result<(V, R), E> {
+/// Runs a file with its own global scope and returns it as a module. Every file is only run once,
+/// importing it again returns the same module. Addresses after the path define those names of the module here.
+pub fn _import(args: Vec<V>, context: &mut Context, _: &Position, poses: &[&Position]) -> Result<(V, R), E> {
+    let V::String(path) = &args[0] else { return Ok((V::Null, R::None)) };
+    let Some(path) = &context.resolve(path) else {
+        context.trace(poses[0]);
+        return Err(E::FileNotFound(path.clone()))
+    };
+    let key = fs::canonicalize(path).map(|p| p.to_string_lossy().to_string()).unwrap_or(path.clone());
+    let module = match context.modules.get(&key) {
+        Some(module) => module.clone(),
+        None => {
+            if let Some(idx) = context.importing.iter().position(|p| p == &key) {
+                let mut paths = context.importing[idx..].to_vec();
+                paths.push(key);
+                context.trace(poses[0]);
+                return Err(E::CyclicImport(paths))
+            }
+            context.importing.push(key.clone());
+            let global = Rc::new(RefCell::new(Scope::new()));
+            let env = context.enter(Env { scopes: vec![Rc::new(RefCell::new(Scope::new()))], global: global.clone() });
+            let callers = std::mem::take(&mut context.callers);
+            let _path = std::mem::replace(&mut context.path, path.clone());
+            let res = runfile(path, context);
+            context.path = _path;
+            context.callers = callers;
+            context.enter(env);
+            context.importing.pop();
+            if res.is_err() { context.trace(poses[0]); }
+            res?;
+            let module = V::Module(path.clone(), global);
+            context.modules.insert(key, module.clone());
+            module
+        }
+    };
+    for i in 1..args.len() {
+        let V::Addr(word, _) = &args[i] else {
+            context.trace(poses[i]);
+            return Err(E::ExpectedType { typ: Type::Addr, recv_typ: args[i].typ() })
+        };
+        let value = module.field(word);
+        if matches!(value, V::Null) {
+            context.trace(poses[i]);
+            return Err(E::NotDefined(word.clone()))
+        }
+        if context.def(word, &value).is_err() {
+            context.trace(poses[i]);
+            return Err(E::AlreadyDefined(word.clone()))
+        }
+        if let V::Module(_, global) = &module {
+            if let Some(doc) = global.borrow().doc(word) { context.global.borrow_mut().document(word, doc) }
+        }
+    }
+    Ok((module, R::None))
+}
+pub fn _assert(args: Vec<V>, context: &mut Context, pos: &Position, poses: &[&Position]) -> Result<(V, R), E> {
     if args[0] == V::Bool(false) {
-        context.trace(&pos);
+        context.trace(pos);
         return Err(E::AssertError)
     }
     Ok((V::Null, R::None))
 }
 
-fn patt(pattern: Vec<Type>) -> Box<V> { Box::new(V::Pattern(pattern)) }
-fn npatt() -> Box<V> { Box::new(V::Null) }
-
-pub fn funx_context(path: &String) -> Context {
+pub fn funx_context(path: &str) -> Context {
     let mut context = Context::new(path);
-    let _ = context.def(&"var".to_string(),
-    &V::NativFunction(patt(vec![Type::Addr, Type::Any]), _var));
-    let _ = context.def(&"set".to_string(),
-    &V::NativFunction(patt(vec![Type::Addr, Type::Any]), _set));
-    let _ = context.def(&"def".to_string(),
-    &V::NativFunction(patt(vec![Type::Addr, Type::Any]), _def));
-    let _ = context.def(&"get".to_string(),
-    &V::NativFunction(patt(vec![Type::Addr]), _get));
+    let _ = context.register_native("var", Some(vec![Type::Addr, Type::Any]), _var);
+    let _ = context.register_native("set", Some(vec![Type::Addr, Type::Any]), _set);
+    let _ = context.register_native("def", Some(vec![Type::Addr, Type::Any]), _def);
+    let _ = context.register_native("get", Some(vec![Type::Addr]), _get);
 
-    let _ = context.def(&"if".to_string(),
-    &V::NativFunction(patt(vec![Type::Bool, Type::some(), Type::Any]), _if));
-    let _ = context.def(&"while".to_string(),
-    &V::NativFunction(patt(vec![Type::Union(vec![Type::Bool, Type::Closure]), Type::Closure]), _while));
+    let _ = context.register_native("if", Some(vec![Type::Bool, Type::some(), Type::Any]), _if);
+    let _ = context.register_native("while", Some(vec![Type::Union(vec![Type::Bool, Type::Closure]), Type::Closure]), _while);
+    let _ = context.register_native("for", Some(vec![Type::Addr, Type::number(), Type::Union(vec![Type::Bool, Type::Closure]), Type::Closure, Type::Closure]), _for);
+    let _ = context.register_native("return", None, _return);
+    let _ = context.register_native("break", None, _break);
+    let _ = context.register_native("continue", None, _continue);
 
-    let _ = context.def(&"+".to_string(),
-    &V::NativFunction(npatt(), _add));
-    let _ = context.def(&"-".to_string(),
-    &V::NativFunction(npatt(), _sub));
-    let _ = context.def(&"*".to_string(),
-    &V::NativFunction(npatt(), _mul));
-    let _ = context.def(&"/".to_string(),
-    &V::NativFunction(npatt(), _div));
+    let _ = context.register_native("+", None, _add);
+    let _ = context.register_native("-", None, _sub);
+    let _ = context.register_native("*", None, _mul);
+    let _ = context.register_native("/", None, _div);
+    let _ = context.register_native("//", None, _int_div);
+    let _ = context.register_native("mod", None, _mod);
+    let _ = context.register_native("pow", None, _pow);
+    let _ = context.register_native("abs", Some(vec![Type::number()]), _abs);
+    let _ = context.register_native("bit-and", Some(vec![Type::Int, Type::Int]), _bit_and);
+    let _ = context.register_native("bit-or", Some(vec![Type::Int, Type::Int]), _bit_or);
+    let _ = context.register_native("bit-xor", Some(vec![Type::Int, Type::Int]), _bit_xor);
+    let _ = context.register_native("bit-not", Some(vec![Type::Int]), _bit_not);
+    let _ = context.register_native("shl", Some(vec![Type::Int, Type::Int]), _shl);
+    let _ = context.register_native("shr", Some(vec![Type::Int, Type::Int]), _shr);
 
-    let _ = context.def(&"=".to_string(),
-    &V::NativFunction(npatt(), _eq));
-    let _ = context.def(&"lt".to_string(),
-    &V::NativFunction(patt(vec![Type::number(), Type::number()]), _lt));
-    let _ = context.def(&"gt".to_string(),
-    &V::NativFunction(patt(vec![Type::number(), Type::number()]), _gt));
+    let _ = context.register_native("=", None, _eq);
+    let _ = context.register_native("lt", Some(vec![Type::number(), Type::number()]), _lt);
+    let _ = context.register_native("gt", Some(vec![Type::number(), Type::number()]), _gt);
     
-    let _ = context.def(&"union".to_string(),
-    &V::NativFunction(npatt(), _union));
-    let _ = context.def(&"exclude".to_string(),
-    &V::NativFunction(npatt(), _exclude));
+    let _ = context.register_native("union", None, _union);
+    let _ = context.register_native("exclude", None, _exclude);
     
-    let _ = context.def(&"print".to_string(),
-    &V::NativFunction(npatt(), _print));
-    let _ = context.def(&"load".to_string(),
-    &V::NativFunction(npatt(), _load));
-    let _ = context.def(&"assert".to_string(),
-    &V::NativFunction(npatt(), _assert));
+    let _ = context.register_native("map-get", Some(vec![Type::Map, Type::key()]), _map_get);
+    let _ = context.register_native("map-insert", Some(vec![Type::Map, Type::key(), Type::Any]), _map_insert);
+    let _ = context.register_native("map-remove", Some(vec![Type::Map, Type::key()]), _map_remove);
+    let _ = context.register_native("keys", Some(vec![Type::Map]), _keys);
+    let _ = context.register_native("values", Some(vec![Type::Map]), _values);
+    let _ = context.register_native("has", Some(vec![Type::Map, Type::key()]), _has);
+
+    let _ = context.register_native("len", Some(vec![Type::Union(vec![Type::String, Type::vector(), Type::Map])]), _len);
+    let _ = context.register_native("index", Some(vec![Type::Union(vec![Type::String, Type::vector()]), Type::Int]), _index);
+    let _ = context.register_native("push", Some(vec![Type::vector()]), _push);
+    let _ = context.register_native("pop", Some(vec![Type::vector()]), _pop);
+    let _ = context.register_native("slice", Some(vec![Type::vector(), Type::Int, Type::option(Type::Int)]), _slice);
+    let _ = context.register_native("concat", None, _concat);
+    let _ = context.register_native("reverse", Some(vec![Type::vector()]), _reverse);
+    let _ = context.register_native("sort", Some(vec![Type::vector(), Type::option(Type::callable())]), _sort);
+    let _ = context.register_native("map-vec", Some(vec![Type::vector(), Type::callable()]), _map_vec);
+    let _ = context.register_native("filter", Some(vec![Type::vector(), Type::callable()]), _filter);
+    let _ = context.register_native("reduce", Some(vec![Type::vector(), Type::Any, Type::callable()]), _reduce);
+    let _ = context.register_native("each", Some(vec![Type::vector(), Type::callable()]), _each);
+
+    let _ = context.register_native("substr", Some(vec![Type::String, Type::Int, Type::option(Type::Int)]), _substr);
+    let _ = context.register_native("split", Some(vec![Type::String, Type::String]), _split);
+    let _ = context.register_native("join", Some(vec![Type::vector(), Type::option(Type::String)]), _join);
+    let _ = context.register_native("trim", Some(vec![Type::String]), _trim);
+    let _ = context.register_native("upper", Some(vec![Type::String]), _upper);
+    let _ = context.register_native("lower", Some(vec![Type::String]), _lower);
+    let _ = context.register_native("contains", Some(vec![Type::String, Type::String]), _contains);
+    let _ = context.register_native("starts-with", Some(vec![Type::String, Type::String]), _starts_with);
+    let _ = context.register_native("ends-with", Some(vec![Type::String, Type::String]), _ends_with);
+    let _ = context.register_native("replace", Some(vec![Type::String, Type::String, Type::String]), _replace);
+    let _ = context.register_native("find", Some(vec![Type::String, Type::String]), _find);
+    let _ = context.register_native("repeat", Some(vec![Type::String, Type::Int]), _repeat);
+    let _ = context.register_native("format", Some(vec![Type::String]), _format);
+
+    let _ = context.register_native("try", Some(vec![Type::callable(), Type::option(Type::callable())]), _try);
+    let _ = context.register_native("throw", None, _throw);
+    let _ = context.register_native("error-message", Some(vec![Type::Error]), _error_message);
+    let _ = context.register_native("error-kind", Some(vec![Type::Error]), _error_kind);
+    let _ = context.register_native("error-code", Some(vec![Type::Error]), _error_code);
+    let _ = context.register_native("error-value", Some(vec![Type::Error]), _error_value);
+    let _ = context.register_native("error-trace", Some(vec![Type::Error]), _error_trace);
+    let _ = context.register_native("error-stack", Some(vec![Type::Error]), _error_stack);
+
+    let _ = context.register_native("print", None, _print);
+    let _ = context.register_native("doc", Some(vec![Type::Union(vec![Type::Addr, Type::String])]), _doc);
+    let _ = context.register_native("load", None, _load);
+    let _ = context.register_native("import", Some(vec![Type::String]), _import);
+    let _ = context.register_native("assert", None, _assert);
 
     context
 }
\ No newline at end of file
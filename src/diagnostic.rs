@@ -0,0 +1,149 @@
+use std::fs;
+use std::str::FromStr;
+use crate::error::*;
+use crate::context::*;
+use crate::position::*;
+
+static RED: &str = "\x1b[1;31m";
+static BLUE: &str = "\x1b[1;34m";
+static CYAN: &str = "\x1b[1;36m";
+static BOLD: &str = "\x1b[1m";
+static RESET: &str = "\x1b[0m";
+/// Spans longer than this only show their first and last lines.
+static MAX_LINES: usize = 4;
+
+/// How the binary prints errors: for people to read or as JSON lines for tools.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat { Human, Json }
+impl FromStr for ErrorFormat {
+    type Err = ();
+    fn from_str(format: &str) -> Result<Self, ()> {
+        match format {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(())
+        }
+    }
+}
+
+/// Renders errors with the source lines of every trace frame and note, the spans underlined by carets.
+pub struct Renderer<'a> {
+    pub context: &'a Context,
+    pub color: bool
+}
+impl<'a> Renderer<'a> {
+    pub fn new(context: &'a Context, color: bool) -> Self { Self { context, color } }
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color { format!("{style}{text}{RESET}") } else { text.to_string() }
+    }
+    /// The text of the file at `path`, from the sources the context has run or else from disk.
+    fn source(&self, path: &String) -> Option<String> {
+        match self.context.sources.get(path) {
+            Some(text) => Some(text.clone()),
+            None => fs::read_to_string(path).ok()
+        }
+    }
+    pub fn render(&self, e: &E) -> String {
+        if let E::Errors(errors) = e {
+            return errors.iter().map(|(e, trace)| self.error(e, trace, &[], &[])).collect::<Vec<String>>().join("\n")
+        }
+        let backtrace = self.context.backtrace.as_deref().unwrap_or_default();
+        self.error(e, &self.context.trace, &self.context.notes, backtrace)
+    }
+    fn error(&self, e: &E, trace: &[(Position, String)], notes: &[(String, Position, String)], backtrace: &[Frame]) -> String {
+        let width = trace.iter().map(|(pos, _)| pos).chain(notes.iter().map(|(_, pos, _)| pos))
+            .map(|pos| (pos.0.end + 1).to_string().len()).max().unwrap_or(1);
+        let header = format!("ERROR[{}]", e.code());
+        let mut string = format!("{}{}\n", self.paint(RED, &header), self.paint(BOLD, &format!(": {}", e.message())));
+        for (pos, path) in trace.iter() {
+            string.push_str(&self.snippet(pos, path, '^', RED, width));
+        }
+        for (note, pos, path) in notes.iter() {
+            string.push_str(&format!("{}: {note}\n", self.paint(CYAN, "note")));
+            string.push_str(&self.snippet(pos, path, '-', CYAN, width));
+        }
+        if !backtrace.is_empty() {
+            string.push_str(&format!("{}\n", self.paint(BOLD, "backtrace:")));
+            let width = (backtrace.len() - 1).to_string().len();
+            let frames: Vec<&Frame> = backtrace.iter().rev().collect();
+            let mut i = 0;
+            while i < frames.len() {
+                // a recursion shows up as the same call over and over
+                let repeated = frames[i..].iter().take_while(|frame| **frame == frames[i]).count();
+                string.push_str(&format!("  {:>width$}: {}\n", i, frames[i]));
+                if repeated > 1 { string.push_str(&format!("  {:width$}  ... repeated {} more times\n", "", repeated - 1)) }
+                i += repeated;
+            }
+        }
+        string
+    }
+    /// The location of `pos` followed by the lines it spans, each underlined from where the span starts to where it ends.
+    fn snippet(&self, pos: &Position, path: &String, mark: char, style: &str, width: usize) -> String {
+        let mut string = format!("{}{path}:{}:{}\n", self.paint(BLUE, &format!("{:width$}--> ", "")), pos.0.start + 1, pos.1.start + 1);
+        let Some(text) = self.source(path) else { return string };
+        let lines: Vec<&str> = text.split('\n').map(|line| line.trim_end_matches('\r')).collect();
+        if pos.0.start >= lines.len() { return string }
+        let gutter = self.paint(BLUE, &format!("{:width$} |", ""));
+        string.push_str(&format!("{gutter}\n"));
+        let end = pos.0.end.min(lines.len() - 1).max(pos.0.start);
+        for (ln, line) in lines.iter().enumerate().take(end + 1).skip(pos.0.start) {
+            if end - pos.0.start >= MAX_LINES && ln > pos.0.start + 1 && ln + 1 < end {
+                if ln == pos.0.start + 2 { string.push_str(&format!("{}\n", self.paint(BLUE, "..."))) }
+                continue
+            }
+            let len = line.chars().count();
+            let start = if ln == pos.0.start { pos.1.start } else { len - line.trim_start().chars().count() };
+            let stop = if ln == pos.0.end { pos.1.end } else { len };
+            let stop = stop.max(start + 1);
+            // tabs stay tabs so the carets line up with the text above
+            let indent: String = line.chars().take(start).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            let marks = mark.to_string().repeat(stop - start);
+            string.push_str(&format!("{} {line}\n", self.paint(BLUE, &format!("{:>width$} |", ln + 1))));
+            string.push_str(&format!("{gutter} {indent}{}\n", self.paint(style, &marks)));
+        }
+        string
+    }
+    /// Renders the error as one line of JSON, or one line per error for [`E::Errors`].
+    /// Lines and columns count from 1 like in the human readable output, `column_end` is exclusive.
+    pub fn json(&self, e: &E) -> String {
+        if let E::Errors(errors) = e {
+            return errors.iter().map(|(e, trace)| self.json_error(e, trace, &[], &[])).collect::<Vec<String>>().join("\n")
+        }
+        let backtrace = self.context.backtrace.as_deref().unwrap_or_default();
+        self.json_error(e, &self.context.trace, &self.context.notes, backtrace)
+    }
+    fn json_error(&self, e: &E, trace: &[(Position, String)], notes: &[(String, Position, String)], backtrace: &[Frame]) -> String {
+        let span = match trace.first() {
+            Some((pos, path)) => json_span(pos, path),
+            None => "\"file\":null".to_string()
+        };
+        let trace: Vec<String> = trace.iter().map(|(pos, path)| format!("{{{}}}", json_span(pos, path))).collect();
+        let notes: Vec<String> = notes.iter()
+            .map(|(note, pos, path)| format!("{{\"message\":{},{}}}", json_string(note), json_span(pos, path))).collect();
+        let backtrace: Vec<String> = backtrace.iter().rev()
+            .map(|frame| format!("{{\"name\":{},{}}}", json_string(&frame.name), json_span(&frame.pos, &frame.path))).collect();
+        format!("{{\"code\":{},\"kind\":{},\"message\":{},{span},\"trace\":[{}],\"notes\":[{}],\"backtrace\":[{}]}}",
+            json_string(e.code()), json_string(e.kind()), json_string(&e.message()), trace.join(","), notes.join(","), backtrace.join(","))
+    }
+}
+
+fn json_span(pos: &Position, path: &str) -> String {
+    format!("\"file\":{},\"line_start\":{},\"line_end\":{},\"column_start\":{},\"column_end\":{}",
+        json_string(path), pos.0.start + 1, pos.0.end + 1, pos.1.start + 1, pos.1.end + 1)
+}
+fn json_string(text: &str) -> String {
+    let mut string = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            c if (c as u32) < 0x20 => string.push_str(&format!("\\u{:04x}", c as u32)),
+            c => string.push(c)
+        }
+    }
+    string.push('"');
+    string
+}
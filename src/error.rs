@@ -1,8 +1,8 @@
-use std::cmp::min;
-use std::fs;
 use crate::values::*;
 use crate::context::*;
 use crate::lexer::*;
+use crate::position::*;
+use crate::diagnostic::*;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum E {
@@ -17,47 +17,145 @@ pub enum E {
     Immutable(String),
     BinaryOperation { type1: Type, type2: Type },
     UnaryOperation(Type),
-    PatternMissmatch { pattern1: V, pattern2: V },
+    PatternMissmatch { pattern1: Box<V>, pattern2: Box<V> },
     ExpectedLen { len: usize, recv_len: usize },
+    MapEntry(usize),
     AssertError,
+    Thrown(V),
+    CyclicImport(Vec<String>),
+    Unclosed(T),
+    UnclosedString,
+    Errors(Vec<(E, Vec<(Position, String)>)>),
+    Escape(String),
+    Number(String),
+    NumberRange(String),
+    UnclosedComment,
+    Overflow(String),
+    DivisionByZero,
+    StackOverflow(usize),
+    OutOfFuel,
+    StackSize(usize),
 }
 impl E {
-    pub fn display(&self, context: &Context) -> String {
-        let mut string: String = format!("{self}");
-        string.push_str("\n");
-        for (pos, path) in context.trace.iter() {
-            let text = fs::read_to_string(path).unwrap_or_else(|_|"".to_string());
-            if text.len() > 0 {
-                let lines: Vec<&str> = text.split("\n").collect();
-                string.push_str(format!("{}:{}:{} - {}:{}\n",
-                &context.path, pos.0.start + 1, pos.1.start + 1, pos.0.end + 1, pos.1.end + 1).as_str());
-                string.push_str(lines[min(pos.0.start, lines.len())..min(pos.0.end + 1, lines.len())].join("\n").as_str());
-                string.push_str("\n");
-            } else {
-                string.push_str(text.as_str());
-                string.push_str("\n");
-            }
+    pub fn message(&self) -> String {
+        match self {
+            Self::TargetNotFound(path) => format!("target file {path:?} could not be found"),
+            Self::FileNotFound(path) => format!("file {path:?} could not be found"),
+            Self::Char(char) => format!("bad character {char:?}"),
+            Self::UnexpectedToken(token) => format!("unexpected {}", token.name()),
+            Self::HeadOperation(value) => format!("unexpected {} as head operation", value.typ()),
+            Self::ExpectedType{ typ, recv_typ } => format!("expected type {typ} but got type {recv_typ}"),
+            Self::NotDefined(word) => format!("word {word} is not defined"),
+            Self::AlreadyDefined(word) => format!("word {word} is already defined"),
+            Self::Immutable(word) => format!("word {word} is immutable"),
+            Self::BinaryOperation{ type1, type2 } => format!("illegal operation between type {type1} and type {type2}"),
+            Self::UnaryOperation(typ) => format!("illegal operation on type {typ}"),
+            Self::PatternMissmatch { pattern1, pattern2 } => format!("pattern {pattern1} does not match {pattern2}"),
+            Self::ExpectedLen { len, recv_len } => format!("expected pattern to be at least of length {len} not {recv_len}"),
+            Self::MapEntry(len) => format!("expected a key and a value as map entry but got {len} values"),
+            Self::AssertError => "assertion is false".to_string(),
+            Self::Thrown(value) => value.to_string(),
+            Self::CyclicImport(paths) => format!("cyclic import {}", paths.join(" -> ")),
+            Self::Unclosed(token) => format!("{} is never closed", token.name()),
+            Self::UnclosedString => "string is never closed".to_string(),
+            Self::Errors(errors) => errors.iter().map(|(e, _)| e.message()).collect::<Vec<String>>().join("\n"),
+            Self::Escape(escape) => format!("invalid escape sequence {escape}"),
+            Self::Number(number) => format!("invalid number literal {number}"),
+            Self::NumberRange(number) => format!("number literal {number} is out of range"),
+            Self::UnclosedComment => "comment is never closed".to_string(),
+            Self::Overflow(op) => format!("integer overflow in {op}"),
+            Self::DivisionByZero => "integer division by zero".to_string(),
+            Self::StackOverflow(depth) => format!("maximum call depth of {depth} exceeded"),
+            Self::OutOfFuel => "evaluation ran out of fuel".to_string(),
+            Self::StackSize(bytes) => format!("calls took more than {bytes} bytes of stack"),
         }
-        string
+    }
+    /// The name of the variant, used by funx code to tell errors apart.
+    pub fn kind(&self) -> &str {
+        match self {
+            Self::TargetNotFound(_) => "target-not-found",
+            Self::FileNotFound(_) => "file-not-found",
+            Self::Char(_) => "char",
+            Self::UnexpectedToken(_) => "unexpected-token",
+            Self::HeadOperation(_) => "head-operation",
+            Self::ExpectedType { .. } => "expected-type",
+            Self::NotDefined(_) => "not-defined",
+            Self::AlreadyDefined(_) => "already-defined",
+            Self::Immutable(_) => "immutable",
+            Self::BinaryOperation { .. } => "binary-operation",
+            Self::UnaryOperation(_) => "unary-operation",
+            Self::PatternMissmatch { .. } => "pattern-missmatch",
+            Self::ExpectedLen { .. } => "expected-len",
+            Self::MapEntry(_) => "map-entry",
+            Self::AssertError => "assert",
+            Self::Thrown(_) => "thrown",
+            Self::CyclicImport(_) => "cyclic-import",
+            Self::Unclosed(_) => "unclosed",
+            Self::UnclosedString => "unclosed-string",
+            Self::Errors(_) => "errors",
+            Self::Escape(_) => "escape",
+            Self::Number(_) => "number",
+            Self::NumberRange(_) => "number-range",
+            Self::UnclosedComment => "unclosed-comment",
+            Self::Overflow(_) => "overflow",
+            Self::DivisionByZero => "division-by-zero",
+            Self::StackOverflow(_) => "stack-overflow",
+            Self::OutOfFuel => "out-of-fuel",
+            Self::StackSize(_) => "stack-size",
+        }
+    }
+    /// A stable code for the variant. Codes are never reused, new variants get the next free number.
+    pub fn code(&self) -> &str {
+        match self {
+            Self::TargetNotFound(_) => "F0001",
+            Self::FileNotFound(_) => "F0002",
+            Self::Char(_) => "F0003",
+            Self::UnexpectedToken(_) => "F0004",
+            Self::HeadOperation(_) => "F0005",
+            Self::ExpectedType { .. } => "F0006",
+            Self::NotDefined(_) => "F0007",
+            Self::AlreadyDefined(_) => "F0008",
+            Self::Immutable(_) => "F0009",
+            Self::BinaryOperation { .. } => "F0010",
+            Self::UnaryOperation(_) => "F0011",
+            Self::PatternMissmatch { .. } => "F0012",
+            Self::ExpectedLen { .. } => "F0013",
+            Self::MapEntry(_) => "F0014",
+            Self::AssertError => "F0015",
+            Self::Thrown(_) => "F0016",
+            Self::CyclicImport(_) => "F0017",
+            Self::Unclosed(_) => "F0018",
+            Self::UnclosedString => "F0019",
+            Self::Errors(_) => "F0020",
+            Self::Escape(_) => "F0021",
+            Self::Number(_) => "F0022",
+            Self::NumberRange(_) => "F0023",
+            Self::UnclosedComment => "F0024",
+            Self::Overflow(_) => "F0025",
+            Self::DivisionByZero => "F0026",
+            Self::StackOverflow(_) => "F0027",
+            Self::OutOfFuel => "F0028",
+            Self::StackSize(_) => "F0029",
+        }
+    }
+    /// Renders the error with the source lines of its trace, see [`Renderer`].
+    pub fn display(&self, context: &Context) -> String {
+        Renderer::new(context, false).render(self)
+    }
+    /// Like [`E::display`] but highlighted with terminal colors.
+    pub fn display_color(&self, context: &Context) -> String {
+        Renderer::new(context, true).render(self)
+    }
+    /// Renders the error as JSON lines, see [`Renderer::json`].
+    pub fn display_json(&self, context: &Context) -> String {
+        Renderer::new(context, false).json(self)
     }
 }
 impl std::fmt::Display for E {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::TargetNotFound(path) => write!(f, "ERROR: target file {path:?} could not be found"),
-            Self::FileNotFound(path) => write!(f, "ERROR: file {path:?} could not be found"),
-            Self::Char(char) => write!(f, "ERROR: bad character {char:?}"),
-            Self::UnexpectedToken(token) => write!(f, "ERROR: unexpected {}", token.name()),
-            Self::HeadOperation(value) => write!(f, "ERROR: unexpected {} as head operation", value.typ()),
-            Self::ExpectedType{ typ, recv_typ } => write!(f, "ERROR: expected type {typ} but got type {recv_typ}"),
-            Self::NotDefined(word) => write!(f, "ERROR: word {word} is not defined"),
-            Self::AlreadyDefined(word) => write!(f, "ERROR: word {word} is already defined"),
-            Self::Immutable(word) => write!(f, "ERROR: word {word} is immutable"),
-            Self::BinaryOperation{ type1, type2 } => write!(f, "ERROR: illegal operation between type {type1} and type {type2}"),
-            Self::UnaryOperation(typ) => write!(f, "ERROR: illegal operation on type {typ}"),
-            Self::PatternMissmatch { pattern1, pattern2 } => write!(f, "ERROR: pattern {pattern1} does not match {pattern2}"),
-            Self::ExpectedLen { len, recv_len } => write!(f, "ERROR: expected pattern to be at least of length {len} not {recv_len}"),
-            Self::AssertError => write!(f, "ERROR: assertion is false"),
+        if let Self::Errors(errors) = self {
+            return write!(f, "{}", errors.iter().map(|(e, _)| e.to_string()).collect::<Vec<String>>().join("\n"))
         }
+        write!(f, "ERROR: {}", self.message())
     }
 }
\ No newline at end of file
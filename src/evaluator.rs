@@ -3,67 +3,171 @@ use crate::error::*;
 use crate::values::*;
 use crate::context::*;
 use crate::parser::*;
+use crate::vm::{self, Backend};
+use std::collections::BTreeMap;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum R { None, Return, Break, Continue }
 
+/// Calls `head_value` with `args`. Calls of closures and functions are recorded on the call stack,
+/// which is kept as the backtrace of an error raised inside them. The call a closure makes in tail position
+/// takes its place on the call stack and is made here in a loop, so tail recursion does not grow the Rust stack.
 pub fn eval(head_value: V, head: &Node, args: Vec<V>, types: Vec<Type>, poses: Vec<&Position>, node: &Node, context: &mut Context) -> Result<(V, R), E> {
+    if !matches!(head_value, V::Closure(..) | V::Function(..)) {
+        return apply(head_value, head, args, types, poses, node, context)
+    }
+    // a function call is where a signal ends, also if the function made its return value by a tail call,
+    // so a `break` or `continue` outside of a loop does not leave the loop of the caller
+    let mut function = matches!(head_value, V::Function(..));
+    // whether a closure outside of any function made a tail call as the argument of its `return`,
+    // whose value it still has to return once the call is made
+    let mut returning = false;
+    let here = stack_address();
+    if context.stack.is_empty() { context.stack_base = here }
+    let overflow = if context.stack.len() >= context.max_depth {
+        Some(E::StackOverflow(context.stack.len()))
+    } else if here.abs_diff(context.stack_base) > context.max_stack {
+        Some(E::StackSize(context.max_stack))
+    } else {
+        None
+    };
+    if let Some(e) = overflow {
+        context.trace(&node.1);
+        if context.backtrace.is_none() { context.backtrace = Some(context.stack.clone()) }
+        return Err(e)
+    }
+    context.stack.push(Frame { name: name(head), pos: node.1.clone(), path: context.path.clone() });
+    let mut res = step(head_value, head, args, types, poses, node, context);
+    while let Ok(Step::Call(call)) = res {
+        let Call { head_value, head, args, types, poses, node, env, path, returns } = *call;
+        returning |= returns && !function;
+        function |= matches!(head_value, V::Function(..));
+        *context.stack.last_mut().unwrap() = Frame { name: name(&head), pos: node.1.clone(), path: path.clone() };
+        res = inside(&env, &path, None, context, |context| step(head_value, &head, args, types, poses.iter().collect(), &node, context));
+    }
+    if res.is_err() && context.backtrace.is_none() {
+        context.backtrace = Some(context.stack.clone());
+    }
+    context.stack.pop();
+    match res? {
+        Step::Done(value, R::None | R::Return) if returning => Ok((value, R::Return)),
+        Step::Done(value, _) if function => Ok((value, R::None)),
+        Step::Done(value, ret) => Ok((value, ret)),
+        Step::Call(_) => unreachable!()
+    }
+}
+/// The address of a local variable, which tells how far the Rust stack has grown.
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+fn name(head: &Node) -> String {
+    if let N::Word(word) | N::Prelude(word, _) | N::Local(word, ..) = &head.0 { word.clone() } else { "#closure".to_string() }
+}
+
+/// A call in tail position of a closure, left to [`eval`] to make in the scopes and file it was found in.
+/// `returns` tells whether it is the argument of a `return`.
+pub(crate) struct Call {
+    pub head_value: V,
+    pub head: Node,
+    pub args: Vec<V>,
+    pub types: Vec<Type>,
+    pub poses: Vec<Position>,
+    pub node: Node,
+    pub env: Env,
+    pub path: String,
+    pub returns: bool
+}
+/// What calling a closure led to: its value and signal, or the call in tail position it still has to make.
+pub(crate) enum Step { Done(V, R), Call(Box<Call>) }
+
+/// Calls a closure or function, or else applies `head_value` like [`apply`].
+fn step(head_value: V, head: &Node, args: Vec<V>, types: Vec<Type>, poses: Vec<&Position>, node: &Node, context: &mut Context) -> Result<Step, E> {
     match head_value {
-        V::NativFunction(params, f) => {
-            if let V::Pattern(_pattern) = params.as_ref() {
-                for i in 0.._pattern.len() {
-                    if &_pattern[i] != types.get(i).unwrap_or_else(|| &Type::Undefined) {
+        V::Closure(n, closure_path, env) => {
+            inside(&env, &closure_path, Some(&args), context, |context| match context.backend {
+                Backend::Tree => tail(&n.node, true, context),
+                Backend::Vm => vm::call(&n, context)
+            })
+        }
+        V::Function(pattern, value, (decl_pos, decl_path)) => {
+            if let V::Pattern(patt_types) = pattern.as_ref() {
+                for i in 0..patt_types.len() {
+                    if &patt_types[i] != types.get(i).unwrap_or(&Type::Undefined) {
+                        context.note("pattern declared here", &decl_pos, &decl_path);
                         if i < poses.len() {
-                            context.trace(&poses[i]);
-                            return Err(E::ExpectedType { typ: _pattern[i].clone(), recv_typ: types[i].clone() })
+                            context.trace(poses[i]);
+                            return Err(E::ExpectedType { typ: patt_types[i].clone(), recv_typ: types[i].clone() })
                         }
                         context.trace(&node.1);
-                        return Err(E::ExpectedType { typ: _pattern[i].clone(), recv_typ: Type::Undefined })
+                        return Err(E::ExpectedLen { len: patt_types.len(), recv_len: types.len() })
                     }
                 }
-            } else if params.as_ref() != &V::Null {
-                context.trace(&head.1);
-                return Err(E::ExpectedType { typ: Type::Pattern, recv_typ: params.typ() })
+                return step(*value, head, args, types, poses, node, context)
+            }
+            context.trace(&head.1);
+            Err(E::ExpectedType { typ: Type::Pattern, recv_typ: pattern.typ() })
+        }
+        _ => {
+            let (value, ret) = apply(head_value, head, args, types, poses, node, context)?;
+            Ok(Step::Done(value, ret))
+        }
+    }
+}
+/// Checks the types of the arguments of a native function call against the pattern of the native.
+pub(crate) fn check(params: &V, types: &[Type], poses: &[&Position], head: &Node, node: &Node, context: &mut Context) -> Result<(), E> {
+    if let V::Pattern(_pattern) = params {
+        for i in 0.._pattern.len() {
+            if &_pattern[i] != types.get(i).unwrap_or(&Type::Undefined) {
+                if i < poses.len() {
+                    context.trace(poses[i]);
+                    return Err(E::ExpectedType { typ: _pattern[i].clone(), recv_typ: types[i].clone() })
+                }
+                context.trace(&node.1);
+                return Err(E::ExpectedType { typ: _pattern[i].clone(), recv_typ: Type::Undefined })
             }
+        }
+    } else if params != &V::Null {
+        context.trace(&head.1);
+        return Err(E::ExpectedType { typ: Type::Pattern, recv_typ: params.typ() })
+    }
+    Ok(())
+}
+fn apply(head_value: V, head: &Node, args: Vec<V>, types: Vec<Type>, poses: Vec<&Position>, node: &Node, context: &mut Context) -> Result<(V, R), E> {
+    match head_value {
+        V::NativFunction(params, f) => {
+            check(&params, &types, &poses, head, node, context)?;
             f(args, context, &node.1, &poses)
         }
         V::Bool(v) => {
-            if v && args.len() >= 1 {
+            if v && !args.is_empty() {
                 return Ok((args[0].clone(), R::None))
             } else if args.len() >= 2 {
                 return Ok((args[1].clone(), R::None))
             }
-            return Ok((head_value, R::None))
-        }
-        V::Closure(n, closure_path) => {
-            context.push();
-            context.args(&args);
-            let path = context.path.clone();
-            context.path = closure_path;
-            let value_ret = get(&n, context)?;
-            context.path = path;
-            context.pop();
-            return Ok(value_ret)
+            Ok((head_value, R::None))
         }
         V::Type(typ) => {
-            if args.len() == 0 { return Ok((V::Type(typ), R::None)) }
+            if args.is_empty() { return Ok((V::Type(typ), R::None)) }
             match typ {
                 Type::Function => {
                     if args[0].typ() != Type::Pattern {
                         return Err(E::ExpectedType { typ: Type::Pattern, recv_typ: args[0].typ() })
                     }
+                    let decl = (poses[0].clone(), context.path.clone());
                     if args.len() >= 2 {
-                        return Ok((V::Function(Box::new(args[0].clone()), Box::new(args[1].clone())), R::None))
+                        return Ok((V::Function(Box::new(args[0].clone()), Box::new(args[1].clone()), decl), R::None))
                     }
-                    return Ok((V::Function(Box::new(args[0].clone()), Box::new(V::Null)), R::None))
+                    Ok((V::Function(Box::new(args[0].clone()), Box::new(V::Null), decl), R::None))
                 }
                 Type::Vector(_) => {
                     let mut vec_types: Vec<Type> = vec![];
                     for i in 0..args.len() {
                         if let V::Type(typ) = &args[i] {
-                            if !vec_types.contains(&typ) { vec_types.push(typ.clone()) }
+                            if !vec_types.contains(typ) { vec_types.push(typ.clone()) }
                         } else {
-                            context.trace(&poses[i]);
+                            context.trace(poses[i]);
                             return Err(E::ExpectedType { typ: Type::Type, recv_typ: args[i].typ() })
                         }
                     }
@@ -72,31 +176,142 @@ pub fn eval(head_value: V, head: &Node, args: Vec<V>, types: Vec<Type>, poses: V
                 _ => Ok((typ.cast(&args[0]), R::None))
             }
         }
-        V::Function(pattern, value) => {
-            if let V::Pattern(patt_types) = pattern.as_ref() {
-                for i in 0..patt_types.len() {
-                    if &patt_types[i] != types.get(i).unwrap_or_else(|| &Type::Undefined) {
-                        if i < poses.len() {
-                            context.trace(&poses[i]);
-                            return Err(E::ExpectedType { typ: patt_types[i].clone(), recv_typ: types[i].clone() })
-                        }
-                        context.trace(&node.1);
-                        return Err(E::ExpectedLen { len: patt_types.len(), recv_len: types.len() })
-                    }
+        _ => {
+            context.trace(&head.1);
+            Err(E::HeadOperation(head_value.clone()))
+        }
+    }
+}
+
+/// Runs `f` inside the scopes of `env` and the file at `path`, with a new scope holding `args` if there are any.
+pub(crate) fn inside<T>(env: &Env, path: &str, args: Option<&Vec<V>>, context: &mut Context, f: impl FnOnce(&mut Context) -> Result<T, E>) -> Result<T, E> {
+    let caller = context.enter(env.clone());
+    context.callers.push(caller);
+    let _path = std::mem::replace(&mut context.path, path.to_string());
+    if let Some(args) = args {
+        context.push();
+        context.args(args);
+    }
+    let res = f(context);
+    let caller = context.callers.pop().unwrap();
+    context.enter(caller);
+    context.path = _path;
+    res
+}
+/// Evaluates the node of a closure inside the scopes it was created in.
+/// With `args` a new scope holding them is pushed on top, otherwise the closure runs inline in its scope.
+pub fn closure(n: &ClosureNode, path: &str, env: &Env, args: Option<&Vec<V>>, context: &mut Context) -> Result<(V, R), E> {
+    inside(env, path, args, context, |context| match context.backend {
+        Backend::Tree => get(&n.node, context),
+        Backend::Vm => vm::closure(n, context)
+    })
+}
+
+/// Whether `value` is the native function the prelude defines as `word` and not a variable of that name.
+pub(crate) fn prelude_native(value: &V, word: &String, context: &Context) -> bool {
+    let V::NativFunction(_, f) = value else { return false };
+    matches!(context.prelude.get(word), Some(V::NativFunction(_, native)) if Rc::ptr_eq(f, native))
+}
+/// Like [`get`] for the node of a called closure, but instead of making a call whose value would be the value
+/// of the closure it returns it as [`Step::Call`]. `tail` tells whether the value of `node` is the value of the closure,
+/// which is passed on to the argument of `return` and to the branch `if` takes.
+fn tail(node: &Node, tail: bool, context: &mut Context) -> Result<Step, E> {
+    let nodes = match &node.0 {
+        N::Eval(nodes) if !nodes.is_empty() => nodes,
+        N::Body(nodes) => {
+            context.burn(std::slice::from_ref(&node.1))?;
+            for n in nodes {
+                match self::tail(n, false, context)? {
+                    Step::Done(_, R::None) => {}
+                    step => return Ok(step)
                 }
-                return eval(value.as_ref().clone(), head, args, types, poses, node, context)
             }
-            context.trace(&head.1);
-            Err(E::ExpectedType { typ: Type::Pattern, recv_typ: pattern.typ() })
+            return Ok(Step::Done(V::Null, R::None))
         }
         _ => {
-            context.trace(&head.1);
-            Err(E::HeadOperation(head_value.clone()))
+            let (value, ret) = get(node, context)?;
+            return Ok(Step::Done(value, ret))
+        }
+    };
+    context.burn(std::slice::from_ref(&node.1))?;
+    let (head, arg_nodes) = nodes.split_first().unwrap();
+    let word = if let N::Word(word) | N::Prelude(word, _) | N::Local(word, ..) = &head.0 { Some(word) } else { None };
+    if let Some(word) = word.filter(|word| *word == "return" && arg_nodes.len() == 1) {
+        // looking up a word has no effects, so here the head can be looked at before the argument is evaluated
+        let head_value = match &head.0 {
+            N::Prelude(_, slot) => context.get_prelude(*slot).unwrap_or(V::Null),
+            N::Local(word, depth, slot) => context.get_local(word, *depth, *slot).unwrap_or(V::Null),
+            _ => self::word(word, context)
+        };
+        if prelude_native(&head_value, word, context) {
+            let arg = &arg_nodes[0];
+            let value = match self::tail(arg, true, context)? {
+                Step::Done(value, R::None) => value,
+                Step::Call(mut call) => {
+                    call.returns = true;
+                    return Ok(Step::Call(call))
+                }
+                step => return Ok(step)
+            };
+            let (head_value, _) = get(head, context)?;
+            let typ = value.typ();
+            let (value, ret) = eval(head_value, head, vec![value], vec![typ], vec![&arg.1], node, context)?;
+            return Ok(Step::Done(value, ret))
         }
     }
+    let mut args: Vec<V> = vec![];
+    let mut types: Vec<Type> = vec![];
+    let mut poses: Vec<&Position> = vec![];
+    for n in arg_nodes {
+        let (value, ret) = get(n, context)?;
+        if ret != R::None { return Ok(Step::Done(value, ret)) }
+        poses.push(&n.1);
+        types.push(value.typ());
+        args.push(value);
+    }
+    let (head_value, ret) = get(head, context)?;
+    if ret != R::None { return Ok(Step::Done(head_value, ret)) }
+    if let (Some(word), V::NativFunction(params, _)) = (word.filter(|word| *word == "if"), &head_value) {
+        if prelude_native(&head_value, word, context) {
+            check(params, &types, &poses, head, node, context)?;
+            return match branch(&args) {
+                V::Closure(n, closure_path, env) => inside(env, closure_path, None, context, |context| self::tail(&n.node, tail, context)),
+                value => Ok(Step::Done(value.clone(), R::None))
+            }
+        }
+    }
+    if tail && matches!(head_value, V::Closure(..) | V::Function(..)) {
+        let head = Node(word.map(|word| N::Word(word.clone())).unwrap_or(N::Null), head.1.clone());
+        let poses = poses.into_iter().cloned().collect();
+        let node = Node(N::Null, node.1.clone());
+        return Ok(Step::Call(Box::new(Call { head_value, head, args, types, poses, node, env: context.env(), path: context.path.clone(), returns: false })))
+    }
+    let (value, ret) = eval(head_value, head, args, types, poses, node, context)?;
+    Ok(Step::Done(value, ret))
+}
+
+/// Calls `func` with `args` on behalf of a native function, `pos` being the position of the native's call.
+pub fn call(func: &V, args: Vec<V>, pos: &Position, context: &mut Context) -> Result<(V, R), E> {
+    let node = Node(N::Null, pos.clone());
+    let types: Vec<Type> = args.iter().map(|v| v.typ()).collect();
+    let poses: Vec<&Position> = args.iter().map(|_| pos).collect();
+    eval(func.clone(), &node, args, types, poses, &node, context)
+}
+
+/// The value of `word`, where `a.b` is the field `b` of `a` unless there is a word `a.b`.
+pub fn word(word: &String, context: &Context) -> V {
+    if let Some(value) = context.get(word) { return value }
+    if word.contains('.') {
+        let mut fields = word.split('.');
+        let mut value = context.get(&fields.next().unwrap().to_string()).unwrap_or(V::Null);
+        for field in fields { value = value.field(field); }
+        return value
+    }
+    V::Null
 }
 
 pub fn get(node: &Node, context: &mut Context) -> Result<(V, R), E> {
+    context.burn(std::slice::from_ref(&node.1))?;
     match &node.0 {
         N::Null => Ok((V::Null, R::None)),
         N::Wirldcard => Ok((V::Wirldcard, R::None)),
@@ -107,30 +322,33 @@ pub fn get(node: &Node, context: &mut Context) -> Result<(V, R), E> {
         N::Type(v) => Ok((V::Type(v.clone()), R::None)),
         N::Addr(n) => {
             if let N::Word(addr) = &n.0 {
-                return Ok((V::Addr(addr.clone()), R::None))
+                return Ok((V::Addr(addr.clone(), Some(context.env())), R::None))
             }
             let (mut value, _) = get(n, context)?;
             value = Type::String.cast(&value);
             if let V::String(addr) = value {
-                return Ok((V::Addr(addr), R::None))
+                return Ok((V::Addr(addr, Some(context.env())), R::None))
             }
             context.trace(&node.1);
-            return Err(E::ExpectedType{ typ: Type::String, recv_typ: value.typ() })
+            Err(E::ExpectedType{ typ: Type::String, recv_typ: value.typ() })
         }
         N::Arg(n) => {
+            if let N::Int(idx) = n.0 {
+                return Ok((context.arg(idx).unwrap_or(V::Null), R::None))
+            }
             let (mut value, _) = get(n, context)?;
             value = Type::Int.cast(&value);
             if let V::Int(v) = value {
-                return Ok((context.get(&v.to_string()).unwrap_or_else(||&V::Null).clone(), R::None))
+                return Ok((context.arg(v).unwrap_or(V::Null), R::None))
             }
             context.trace(&node.1);
-            return Err(E::ExpectedType{ typ: Type::String, recv_typ: value.typ() })
+            Err(E::ExpectedType{ typ: Type::String, recv_typ: value.typ() })
         }
-        N::Closure(n) => Ok((V::Closure(n.as_ref().clone(), context.path.clone()), R::None)),
+        N::Closure(n) => Ok((V::Closure(n.clone(), context.path.clone(), context.env()), R::None)),
         N::Pattern(nodes) => {
             let mut types: Vec<Type> = vec![];
             for n in nodes {
-                let (value, _) = get(&n, context)?;
+                let (value, _) = get(n, context)?;
                 if let V::Type(typ) = value {
                     types.push(typ);
                 } else {
@@ -140,27 +358,25 @@ pub fn get(node: &Node, context: &mut Context) -> Result<(V, R), E> {
             }
             Ok((V::Pattern(types), R::None))
         }
-        N::Word(word) => {
-            let v = context.get(word);
-            if let Some(value) = v {
-                return Ok((value.clone(), R::None))
-            }
-            return Ok((V::Null, R::None))
-        }
+        N::Prelude(_, slot) => Ok((context.get_prelude(*slot).unwrap_or(V::Null), R::None)),
+        N::Local(word, depth, slot) => Ok((context.get_local(word, *depth, *slot).unwrap_or(V::Null), R::None)),
+        N::Word(word) => Ok((self::word(word, context), R::None)),
         N::Eval(nodes) => {
-            if nodes.len() == 0 { return Ok((V::Null, R::None)) }
+            if nodes.is_empty() { return Ok((V::Null, R::None)) }
             let mut iter = nodes.iter();
             let head = iter.next().unwrap();
             let mut args: Vec<V> = vec![];
             let mut types: Vec<Type> = vec![];
             let mut poses: Vec<&Position> = vec![];
             for n in iter {
-                let (value, _) = get(n, context)?;
+                let (value, ret) = get(n, context)?;
+                if ret != R::None { return Ok((value, ret)) }
                 poses.push(&n.1);
                 types.push(value.typ());
                 args.push(value);
             }
-            let (head_value, _) = get(head, context)?;
+            let (head_value, ret) = get(head, context)?;
+            if ret != R::None { return Ok((head_value, ret)) }
             eval(head_value, head, args, types, poses, node, context)
         }
         N::Body(nodes) => {
@@ -170,20 +386,30 @@ pub fn get(node: &Node, context: &mut Context) -> Result<(V, R), E> {
             }
             Ok((V::Null, R::None))
         }
+        N::Map(entries) => {
+            let mut map = BTreeMap::new();
+            for (k, v) in entries {
+                let (key, ret) = get(k, context)?;
+                if ret != R::None { return Ok((key, ret)) }
+                let (value, ret) = get(v, context)?;
+                if ret != R::None { return Ok((value, ret)) }
+                if let Some(key) = key.key() {
+                    map.insert(key, value);
+                } else {
+                    context.trace(&k.1);
+                    return Err(E::ExpectedType { typ: Type::key(), recv_typ: key.typ() })
+                }
+            }
+            Ok((V::Map(map), R::None))
+        }
         N::Vector(nodes) => {
             let mut values: Vec<V> = vec![];
-            let mut types: Vec<Type> = vec![];
             for n in nodes {
-                let (value, _) = get(n, context)?;
-                let typ = value.typ();
+                let (value, ret) = get(n, context)?;
+                if ret != R::None { return Ok((value, ret)) }
                 values.push(value);
-                if !types.contains(&typ) { types.push(typ) }
-            }
-            let mut typ = Type::Any;
-            if types.len() > 0 {
-                typ = Type::Union(types);
             }
-            Ok((V::Vector(values, typ), R::None))
+            Ok((V::vector(values), R::None))
         }
     }
 }
\ No newline at end of file
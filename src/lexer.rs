@@ -1,22 +1,21 @@
 use crate::position::*;
 use crate::error::*;
 use crate::values::*;
+use crate::context::*;
 
-static WS: [&str; 4] = [" ", "\n", "\r", "\t"];
-static DIGITS: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
-static SYMBOL: [&str; 14] = ["(", ")", "{", "}", "<", ">", "[", "]", "@", "%", "#", "\"", "'", ";"];
+static SYMBOL: [&str; 15] = ["(", ")", "{", "}", "<", ">", "[", "]", "@", "%", "#", "&", "\"", "'", ";"];
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum T {
     NO,
-    EvalIn, EvalOut, BodyIn, BodyOut, PattIn, PattOut, VecIn, VecOut, Addr, Arg, Closure, End,
+    EvalIn, EvalOut, BodyIn, BodyOut, PattIn, PattOut, VecIn, VecOut, Addr, Arg, Closure, Map, End,
     Null, Wirldcard, Word(String), Int(i64), Float(f64), Bool(bool), String(String),
     Type(Type)
 }
 impl T {
     pub fn name(&self) -> &str {
         match self {
-            Self::NO => "()",
+            Self::NO => "end of input",
             Self::EvalIn => "'('",
             Self::EvalOut => "')'",
             Self::BodyIn => "'{'",
@@ -28,6 +27,7 @@ impl T {
             Self::Addr => "'@'",
             Self::Arg => "'%'",
             Self::Closure => "'#'",
+            Self::Map => "'&'",
             Self::End => "';'",
             Self::Null => "'null'",
             Self::Wirldcard => "'_'",
@@ -53,75 +53,201 @@ impl std::fmt::Display for Token {
     }
 }
 
+/// Walks the text one `char` at a time. `idx` is the byte offset into the text, while `col` counts
+/// characters so positions point at the same place in editors for non-ASCII text.
 pub struct Lexer {
     text: String,
-    idx: usize, ln: usize, col: usize
+    idx: usize, ln: usize, col: usize,
+    /// The doc comment read since the last token.
+    pub doc: Option<String>
 }
 impl Lexer {
-    pub fn new(text: &String) -> Self {
-        Self { text: text.clone(), idx: 0, ln: 0, col: 0 }
+    pub fn new(text: &str) -> Self {
+        let idx = if text.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 };
+        Self { text: text.to_string(), idx, ln: 0, col: 0, doc: None }
     }
+    /// The current character, or `""` at the end of the text.
     pub fn char(&self) -> &str {
-        if self.idx >= self.text.len() { return "" }
-        &self.text[self.idx..self.idx+1]
+        match self.text[self.idx..].chars().next() {
+            Some(c) => &self.text[self.idx..self.idx + c.len_utf8()],
+            None => ""
+        }
+    }
+    pub fn whitespace(&self) -> bool {
+        self.char().chars().next().is_some_and(char::is_whitespace)
     }
     pub fn advance(&mut self) {
+        let len = self.char().len();
         if self.char() == "\n" {
             self.ln += 1; self.col = 0;
-            self.idx += 1;
-        } else {
-            self.idx += 1; self.col += 1;
+        } else if len > 0 {
+            self.col += 1;
         }
+        self.idx += len;
     }
     pub fn pos(&self) -> (usize, usize) { (self.ln, self.col) }
-    pub fn next(&mut self) -> Result<Option<Token>, E> {
-        while WS.contains(&self.char()) { self.advance(); }
-        if self.char() == "$" {
-            self.advance();
-            while self.char() != "\n" { self.advance(); }
-            self.advance();
-            if self.char() == "" { return Ok(None) }
-            while WS.contains(&self.char()) { self.advance(); }
-        }
-        if self.char() == "" { return Ok(None) }
-        let (ln_start, col_start) = self.pos();
-        if DIGITS.contains(&self.char()) {
-            let mut number = String::new();
-            let mut dot = false;
-            while DIGITS.contains(&self.char()) || self.char() == "." {
-                if self.char() == "." {
-                    if dot { break } else { dot = true; }
-                    number.push_str(self.char());
-                    self.advance();
-                } else {
-                    number.push_str(self.char());
+    fn rest(&self) -> &str { &self.text[self.idx..] }
+    /// Skips white space and comments up to the next token: `$` comments to the end of the line, `$( ... )$`
+    /// block comments which can be nested, and `$$` doc comments whose lines are kept in `doc`.
+    fn skip(&mut self, context: &mut Context) -> Result<(), E> {
+        loop {
+            if self.whitespace() {
+                self.advance();
+            } else if self.rest().starts_with("$(") {
+                self.block_comment(context)?;
+            } else if self.rest().starts_with("$$") {
+                self.advance(); self.advance();
+                if self.char() == " " { self.advance(); }
+                let mut line = String::new();
+                while self.char() != "\n" && self.char() != "" {
+                    line.push_str(self.char());
                     self.advance();
                 }
+                let line = line.trim_end();
+                match &mut self.doc {
+                    Some(doc) => { doc.push('\n'); doc.push_str(line) }
+                    None => self.doc = Some(line.to_string())
+                }
+            } else if self.char() == "$" {
+                while self.char() != "\n" && self.char() != "" { self.advance(); }
+            } else {
+                return Ok(())
+            }
+        }
+    }
+    fn block_comment(&mut self, context: &mut Context) -> Result<(), E> {
+        let (ln, col) = self.pos();
+        let mut depth = 0;
+        loop {
+            if self.rest().starts_with("$(") {
+                depth += 1;
+                self.advance(); self.advance();
+            } else if self.rest().starts_with(")$") {
+                depth -= 1;
+                self.advance(); self.advance();
+                if depth == 0 { return Ok(()) }
+            } else if self.char() == "" {
+                context.trace(&Position::new(ln..ln, col..col + 2));
+                return Err(E::UnclosedComment)
+            } else {
+                self.advance();
             }
-            if dot {
-                return Ok(Some(
-                    Token(T::Float(number.parse::<f64>().unwrap()),
-                    Position::new(ln_start..self.ln, col_start..self.col))
-                ))
+        }
+    }
+    /// Whether a number starts here, which is a digit optionally preceded by a sign.
+    fn number(&self) -> bool {
+        let rest = self.rest().strip_prefix(['+', '-']).unwrap_or(self.rest());
+        rest.starts_with(|c: char| c.is_ascii_digit())
+    }
+    /// Skips the `\r` of a `\r\n` line break, so strings only ever contain `\n`.
+    fn crlf(&mut self) {
+        if self.rest().starts_with("\r\n") { self.advance(); }
+    }
+    fn unclosed(&self, (ln, col): (usize, usize), context: &mut Context) -> E {
+        context.trace(&Position::new(ln..ln, col..col + 1));
+        E::UnclosedString
+    }
+    /// The number of `#` if a raw string like `r"..."` or `r#"..."#` starts here.
+    fn raw(&self) -> Option<usize> {
+        let rest = self.rest().strip_prefix('r')?;
+        let hashes = rest.chars().take_while(|c| *c == '#').count();
+        if rest[hashes..].starts_with(['"', '\'']) { Some(hashes) } else { None }
+    }
+    /// Lexes a string in quotes. Line breaks are part of the string, unless they follow a `\`, which
+    /// skips the line break together with the indentation of the next line.
+    fn string(&mut self, context: &mut Context) -> Result<String, E> {
+        let start = self.pos();
+        let end = self.char().to_string();
+        self.advance();
+        let mut string = String::new();
+        loop {
+            self.crlf();
+            match self.char() {
+                "" => return Err(self.unclosed(start, context)),
+                "\\" => if let Some(c) = self.escape(start, context)? { string.push(c) },
+                c if c == end => { self.advance(); return Ok(string) }
+                c => { string.push_str(c); self.advance(); }
             }
-            return Ok(Some(
-                Token(
-                    T::Int(number.parse::<i64>().unwrap()),
-                    Position::new(ln_start..self.ln, col_start..self.col)
-                )
-            ))
         }
-        if self.char() == "\"" || self.char() == "'" {
-            let end = if self.char() == "\"" { "\"" } else { "'" };
-            let mut string = String::new();
+    }
+    /// Lexes a raw string, in which a `\` is just a `\`. It ends at the quote it started with
+    /// followed by as many `#` as it started with.
+    fn raw_string(&mut self, hashes: usize, context: &mut Context) -> Result<String, E> {
+        let start = self.pos();
+        for _ in 0..hashes + 1 { self.advance(); }
+        let end = format!("{}{}", self.char(), "#".repeat(hashes));
+        self.advance();
+        let mut string = String::new();
+        loop {
+            self.crlf();
+            if self.rest().starts_with(&end) {
+                for _ in 0..hashes + 1 { self.advance(); }
+                return Ok(string)
+            }
+            if self.char() == "" { return Err(self.unclosed(start, context)) }
+            string.push_str(self.char());
             self.advance();
-            while self.char() != end {
-                string.push_str(self.char());
+        }
+    }
+    /// Lexes the escape sequence at the current `\`. A line continuation results in no character.
+    fn escape(&mut self, start: (usize, usize), context: &mut Context) -> Result<Option<char>, E> {
+        let (ln, col, idx) = (self.ln, self.col, self.idx);
+        self.advance();
+        self.crlf();
+        let c = self.char().to_string();
+        self.advance();
+        let c = match c.as_str() {
+            "" => return Err(self.unclosed(start, context)),
+            "n" => Some('\n'),
+            "t" => Some('\t'),
+            "r" => Some('\r'),
+            "0" => Some('\0'),
+            "\\" | "\"" | "'" => c.chars().next(),
+            "\n" => {
+                while self.whitespace() { self.advance(); }
+                return Ok(None)
+            }
+            "x" => {
+                let digits: String = self.rest().chars().take(2).collect();
+                if digits.len() == 2 && digits.chars().all(|c| c.is_ascii_hexdigit()) { self.advance(); self.advance(); }
+                u32::from_str_radix(&digits, 16).ok().filter(|code| *code <= 0x7f).and_then(char::from_u32)
+            }
+            "u" => match self.rest().strip_prefix('{').and_then(|rest| rest.split_once('}')).map(|(digits, _)| digits.to_string()) {
+                Some(digits) if !digits.is_empty() && digits.len() <= 6 && digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    for _ in 0..digits.len() + 2 { self.advance(); }
+                    u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32)
+                }
+                _ => None
+            }
+            _ => None
+        };
+        if c.is_none() {
+            context.trace(&Position::new(ln..self.ln, col..self.col));
+            return Err(E::Escape(self.text[idx..self.idx].to_string()))
+        }
+        Ok(c)
+    }
+    pub fn next(&mut self, context: &mut Context) -> Result<Option<Token>, E> {
+        self.skip(context)?;
+        if self.char() == "" { return Ok(None) }
+        let (ln_start, col_start) = self.pos();
+        if self.number() {
+            let mut number = String::new();
+            while !self.whitespace() && !SYMBOL.contains(&self.char()) && self.char() != "" {
+                number.push_str(self.char());
                 self.advance();
             }
-            self.advance();
-            if self.char() == "n" { self.advance(); } else { string = string.replace("\n", "").replace("\r", ""); }
-            string = string.replace("\\n", "\n").replace("\\t", "\t").replace("\\r", "\r");
+            let pos = Position::new(ln_start..self.ln, col_start..self.col);
+            return match number_literal(&number) {
+                Ok(token) => Ok(Some(Token(token, pos))),
+                Err(e) => { context.trace(&pos); Err(e) }
+            }
+        }
+        if self.char() == "\"" || self.char() == "'" || self.raw().is_some() {
+            let string = match self.raw() {
+                Some(hashes) => self.raw_string(hashes, context)?,
+                None => self.string(context)?
+            };
             return Ok(Some(
                 Token(
                     T::String(string),
@@ -147,6 +273,12 @@ impl Lexer {
                 Token(T::Closure, Position::new(ln_start..self.ln, col_start..self.col))
             ))
         }
+        if self.char() == "&" {
+            self.advance();
+            return Ok(Some(
+                Token(T::Map, Position::new(ln_start..self.ln, col_start..self.col))
+            ))
+        }
         if self.char() == ";" {
             self.advance();
             return Ok(Some(
@@ -202,7 +334,7 @@ impl Lexer {
             ))
         }
         let mut word = String::new();
-        while !WS.contains(&self.char()) && !SYMBOL.contains(&self.char()) && self.char() != "" {
+        while !self.whitespace() && !SYMBOL.contains(&self.char()) && self.char() != "" {
             word.push_str(self.char());
             self.advance();
         }
@@ -217,24 +349,84 @@ impl Lexer {
             "bool" => Ok(Some(Token(T::Type(Type::Bool), Position::new(ln_start..self.ln, col_start..self.col)))),
             "str" => Ok(Some(Token(T::Type(Type::String), Position::new(ln_start..self.ln, col_start..self.col)))),
             "vec" => Ok(Some(Token(T::Type(Type::Vector(Box::new(Type::Any))), Position::new(ln_start..self.ln, col_start..self.col)))),
+            "map" => Ok(Some(Token(T::Type(Type::Map), Position::new(ln_start..self.ln, col_start..self.col)))),
+            "module" => Ok(Some(Token(T::Type(Type::Module), Position::new(ln_start..self.ln, col_start..self.col)))),
+            "error" => Ok(Some(Token(T::Type(Type::Error), Position::new(ln_start..self.ln, col_start..self.col)))),
             "nativ-function" => Ok(Some(Token(T::Type(Type::NativFunction), Position::new(ln_start..self.ln, col_start..self.col)))),
             "function" => Ok(Some(Token(T::Type(Type::Function), Position::new(ln_start..self.ln, col_start..self.col)))),
             "addr" => Ok(Some(Token(T::Type(Type::Addr), Position::new(ln_start..self.ln, col_start..self.col)))),
             "closure" => Ok(Some(Token(T::Type(Type::Closure), Position::new(ln_start..self.ln, col_start..self.col)))),
             "pattern" => Ok(Some(Token(T::Type(Type::Pattern), Position::new(ln_start..self.ln, col_start..self.col)))),
             "type" => Ok(Some(Token(T::Type(Type::Type), Position::new(ln_start..self.ln, col_start..self.col)))),
-            _ => return Ok(Some(Token(T::Word(word), Position::new(ln_start..self.ln, col_start..self.col))))
+            _ => Ok(Some(Token(T::Word(word), Position::new(ln_start..self.ln, col_start..self.col))))
         }
     }
 }
 
-pub fn lex(text: &String) -> Result<Vec<Token>, E> {
+/// Parses a number literal: an optional sign followed by a decimal int or float like `1.5e-9`, or an
+/// int with a `0x`, `0o` or `0b` prefix. Digits may be separated by `_`.
+pub fn number_literal(number: &str) -> Result<T, E> {
+    let invalid = || E::Number(number.to_string());
+    let (negative, unsigned) = match number.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, number.strip_prefix('+').unwrap_or(number))
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x") => (16, &unsigned[2..]),
+        Some("0o") => (8, &unsigned[2..]),
+        Some("0b") => (2, &unsigned[2..]),
+        _ => (10, unsigned)
+    };
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        let between_digits = i > 0 && chars[i - 1].is_digit(radix) && chars.get(i + 1).is_some_and(|c| c.is_digit(radix));
+        if *c == '_' && !between_digits { return Err(invalid()) }
+    }
+    let digits = digits.replace('_', "");
+    if radix == 10 && digits.contains(['.', 'e', 'E']) {
+        let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, Some(exponent.strip_prefix(['+', '-']).unwrap_or(exponent))),
+            None => (digits.as_str(), None)
+        };
+        let (int, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+        if int.is_empty() || !all_digits(int) || !all_digits(fraction) || exponent.is_some_and(|e| e.is_empty() || !all_digits(e)) {
+            return Err(invalid())
+        }
+        let value = digits.parse::<f64>().map_err(|_| invalid())?;
+        if value.is_infinite() { return Err(E::NumberRange(number.to_string())) }
+        return Ok(T::Float(if negative { -value } else { value }))
+    }
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) { return Err(invalid()) }
+    let value = i128::from_str_radix(&digits, radix).map_err(|_| E::NumberRange(number.to_string()))?;
+    i64::try_from(if negative { -value } else { value }).map(T::Int).map_err(|_| E::NumberRange(number.to_string()))
+}
+
+/// Lexes `text`, remembering its doc comments for the `def`s they come before, see [`Context::doc_comments`].
+pub fn lex(text: &str, context: &mut Context) -> Result<Vec<Token>, E> {
+    let (tokens, docs) = lex_docs(text, context)?;
+    context.doc_comments.retain(|(path, _, _), _| path != &context.path);
+    for (_, doc, pos) in docs {
+        context.doc_comments.insert((context.path.clone(), pos.0.start, pos.1.start), doc);
+    }
+    Ok(tokens)
+}
+/// Documented words with their doc comments and the position of their `def`.
+pub type Docs = Vec<(String, String, Position)>;
+/// Lexes `text` and also returns the doc comments written right before a `def @word`, with the defined word.
+pub fn lex_docs(text: &str, context: &mut Context) -> Result<(Vec<Token>, Docs), E> {
     let mut lexer = Lexer::new(text);
     let mut tokens: Vec<Token> = vec![];
+    let mut docs: Vec<(usize, String)> = vec![];
     loop {
-        let token = lexer.next()?;
+        let token = lexer.next(context)?;
         if token.is_none() { break }
+        if let Some(doc) = lexer.doc.take() { docs.push((tokens.len(), doc)) }
         tokens.push(token.unwrap());
     }
-    Ok(tokens)
+    let docs = docs.into_iter().filter_map(|(idx, doc)| match tokens.get(idx..idx + 3) {
+        Some([Token(T::Word(def), pos), Token(T::Addr, _), Token(T::Word(word), _)]) if def == "def" => Some((word.clone(), doc, pos.clone())),
+        _ => None
+    }).collect();
+    Ok((tokens, docs))
 }
\ No newline at end of file
@@ -0,0 +1,481 @@
+#![allow(unused_variables)]
+#![allow(dead_code)]
+
+pub mod position;
+pub mod error;
+pub mod values;
+pub mod context;
+pub mod lexer;
+pub mod parser;
+pub mod evaluator;
+pub mod resolver;
+pub mod vm;
+pub mod diagnostic;
+pub use position::Position;
+pub use error::E;
+pub use values::{V, Type, NativFunction};
+pub use context::{Context, Scope, Env, funx_context};
+pub use evaluator::R;
+pub use diagnostic::ErrorFormat;
+pub use vm::Backend;
+
+use std::{env, fs};
+use std::path::PathBuf;
+
+pub static CORE_PATH: &str = "std/core.funx";
+/// The standard library, embedded so the binary does not depend on the working directory.
+pub static CORE: &str = include_str!("../std/core.funx");
+
+pub fn run(path: &str, text: &str, context: &mut Context) -> Result<(V, R), E> {
+    context.sources.insert(path.to_string(), text.to_string());
+    context.trace.clear();
+    context.notes.clear();
+    context.backtrace = None;
+    let tokens = lexer::lex(text, context)?;
+    // println!("{tokens:?}");
+    if tokens.is_empty() { return Ok((V::Null, R::None)) }
+
+    let node = parser::parse(&tokens, context)?;
+    let node = resolver::resolve(node, context);
+    // println!("{node}");
+
+    match context.backend {
+        Backend::Tree => evaluator::get(&node, context),
+        Backend::Vm => vm::get(&node, context)
+    }
+}
+pub fn runfile(path: &str, context: &mut Context) -> Result<(V, R), E> {
+    let res = fs::read_to_string(path);
+    if res.is_err() { return Err(E::FileNotFound(path.to_string())) }
+    let text = res.unwrap();
+    run(path, &text, context)
+}
+pub fn runfile_core(path: &str) -> Result<(V, R), E>{
+    Interpreter::new(path).runfile_core(path)
+}
+
+/// Embedding facade around a funx [`Context`].
+///
+/// The context stays alive between calls, so definitions made by one `run` are visible to the next.
+pub struct Interpreter {
+    pub context: Context,
+    /// Whether [`Interpreter::display`] highlights errors with terminal colors.
+    pub color: bool,
+    pub error_format: ErrorFormat
+}
+impl Interpreter {
+    /// Creates an interpreter with the native functions of [`funx_context`] but without `std/core.funx`.
+    pub fn new(path: &str) -> Self { Self { context: funx_context(path), color: false, error_format: ErrorFormat::Human } }
+    /// Creates an interpreter and loads `std/core.funx` into it.
+    pub fn with_core(path: &str) -> Result<Self, E> {
+        let mut interpreter = Self::new(path);
+        interpreter.load_core()?;
+        Ok(interpreter)
+    }
+    pub fn load_core(&mut self) -> Result<(V, R), E> {
+        let path = self.context.path.clone();
+        self.context.path = CORE_PATH.to_string();
+        let res = run(CORE_PATH, CORE, &mut self.context);
+        if res.is_ok() {
+            self.context.commit_prelude();
+            self.context.path = path;
+        }
+        res
+    }
+    pub fn lex(&mut self, text: &str) -> Result<Vec<lexer::Token>, E> {
+        lexer::lex(text, &mut self.context)
+    }
+    pub fn parse(&mut self, tokens: &[lexer::Token]) -> Result<parser::Node, E> {
+        parser::parse(tokens, &mut self.context)
+    }
+    /// Evaluates `node` with the backend of the context, like [`run`] does.
+    pub fn get(&mut self, node: &parser::Node) -> Result<(V, R), E> {
+        match self.context.backend {
+            Backend::Tree => evaluator::get(node, &mut self.context),
+            Backend::Vm => vm::get(node, &mut self.context)
+        }
+    }
+    /// Runs `text` as if it was the content of the file at `path`.
+    pub fn run(&mut self, path: &str, text: &str) -> Result<(V, R), E> {
+        self.context.path = path.to_string();
+        run(path, text, &mut self.context)
+    }
+    pub fn runfile(&mut self, path: &str) -> Result<(V, R), E> {
+        self.context.path = path.to_string();
+        runfile(path, &mut self.context)
+    }
+    /// Loads `std/core.funx` and then runs the file at `path`.
+    pub fn runfile_core(&mut self, path: &str) -> Result<(V, R), E> {
+        self.load_core()?;
+        self.runfile(path)
+    }
+    /// Defines a global value, failing if the name is already defined.
+    pub fn def(&mut self, word: &str, value: V) -> Result<(), E> {
+        let word = word.to_string();
+        if self.context.def(&word, &value).is_err() { return Err(E::AlreadyDefined(word)) }
+        Ok(())
+    }
+    /// Adds a directory in which `load` and `import` look for files.
+    pub fn add_search_path(&mut self, dir: &str) {
+        self.context.search_path.push(PathBuf::from(dir));
+    }
+    /// Adds every directory of an environment variable like `FUNX_PATH` to the search path.
+    pub fn add_search_path_env(&mut self, var: &str) {
+        if let Some(paths) = env::var_os(var) {
+            self.context.search_path.extend(env::split_paths(&paths));
+        }
+    }
+    /// Registers a native function, see [`Context::register_native`].
+    pub fn register_native<F>(&mut self, word: &str, pattern: Option<Vec<Type>>, f: F) -> Result<(), E>
+    where F: Fn(Vec<V>, &mut Context, &Position, &[&Position]) -> Result<(V, R), E> + 'static {
+        self.context.register_native(word, pattern, f)
+    }
+    pub fn get_var(&self, word: &str) -> Option<V> {
+        self.context.get(&word.to_string())
+    }
+    /// The doc comment of a defined word, see [`Context::doc`].
+    pub fn doc(&self, word: &str) -> Option<String> {
+        self.context.doc(word)
+    }
+    /// How many more nodes may be evaluated, see [`Context::fuel`].
+    pub fn fuel(&self) -> Option<u64> {
+        self.context.fuel
+    }
+    /// Limits how many more nodes may be evaluated, `None` lifts the limit. Once the fuel is used up every run
+    /// fails with [`E::OutOfFuel`] until it is refilled, which `try` can not catch.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.context.fuel = fuel;
+    }
+    /// Adds to the fuel left if it is limited.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = self.context.fuel.as_mut() { *left = left.saturating_add(fuel) }
+    }
+    /// The documented words of the file at `path`, in the order they are defined, see [`lexer::lex_docs`].
+    pub fn docs(&mut self, path: &String) -> Result<Vec<(String, String)>, E> {
+        let text = if path == CORE_PATH { CORE.to_string() } else {
+            fs::read_to_string(path).map_err(|_| E::FileNotFound(path.clone()))?
+        };
+        self.context.path = path.clone();
+        self.context.sources.insert(path.clone(), text.clone());
+        Ok(lexer::lex_docs(&text, &mut self.context)?.1.into_iter().map(|(word, doc, _)| (word, doc)).collect())
+    }
+    /// Renders an error with the trace collected in this interpreter's context, in its [`ErrorFormat`].
+    pub fn display(&self, error: &E) -> String {
+        match self.error_format {
+            ErrorFormat::Json => error.display_json(&self.context),
+            ErrorFormat::Human if self.color => error.display_color(&self.context),
+            ErrorFormat::Human => error.display(&self.context)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    #[test]
+    fn inc() -> Result<(), E> {
+        runfile_core("tests/inc.funx")?; Ok(())
+    }
+    #[test]
+    fn not() -> Result<(), E> {
+        runfile_core("tests/bool.funx")?; Ok(())
+    }
+    #[test]
+    fn types() -> Result<(), E> {
+        runfile_core("tests/types.funx")?; Ok(())
+    }
+    #[test]
+    fn comp() -> Result<(), E> {
+        runfile_core("tests/comp.funx")?; Ok(())
+    }
+    #[test]
+    fn arith() -> Result<(), E> {
+        runfile_core("tests/arith.funx")?; Ok(())
+    }
+    #[test]
+    fn closures() -> Result<(), E> {
+        runfile_core("tests/closures.funx")?; Ok(())
+    }
+    #[test]
+    fn control() -> Result<(), E> {
+        runfile_core("tests/control.funx")?; Ok(())
+    }
+    #[test]
+    fn map() -> Result<(), E> {
+        runfile_core("tests/map.funx")?; Ok(())
+    }
+    #[test]
+    fn vec() -> Result<(), E> {
+        runfile_core("tests/vec.funx")?; Ok(())
+    }
+    #[test]
+    fn str() -> Result<(), E> {
+        runfile_core("tests/str.funx")?; Ok(())
+    }
+    #[test]
+    fn r#try() -> Result<(), E> {
+        runfile_core("tests/try.funx")?; Ok(())
+    }
+    #[test]
+    fn import() -> Result<(), E> {
+        runfile_core("tests/import.funx")?; Ok(())
+    }
+    #[test]
+    fn unicode() -> Result<(), E> {
+        runfile_core("tests/unicode.funx")?;
+        let tokens = Interpreter::new("<test>").lex("\"é\" ünï $ ß\nλ")?;
+        let positions: Vec<Position> = tokens.into_iter().map(|lexer::Token(_, pos)| pos).collect();
+        assert_eq!(positions, vec![Position::new(0..0, 0..3), Position::new(0..0, 4..7), Position::new(1..1, 0..1)]);
+        Ok(())
+    }
+    #[test]
+    fn numbers() -> Result<(), E> {
+        runfile_core("tests/numbers.funx")?; Ok(())
+    }
+    #[test]
+    fn comments() -> Result<(), E> {
+        runfile_core("tests/comments.funx")?; Ok(())
+    }
+    #[test]
+    fn interpreter() -> Result<(), E> {
+        let mut interpreter = Interpreter::with_core("<test>")?;
+        interpreter.run("<test>", "def @x (inc 41)")?;
+        let (value, _) = interpreter.run("<test>", "+ x 0")?;
+        assert_eq!(value, V::Int(42));
+        Ok(())
+    }
+    #[test]
+    fn syntax_errors() {
+        let path = "<test>".to_string();
+        let mut interpreter = Interpreter::new(&path);
+        assert_eq!(interpreter.run(&path, "print (+ 1 2"), Err(E::Unclosed(lexer::T::EvalIn)));
+        assert_eq!(interpreter.context.trace.last().map(|(pos, _)| pos.clone()), Some(Position::new(0..0, 6..7)));
+        assert_eq!(interpreter.run(&path, "print 'abc"), Err(E::UnclosedString));
+        assert_eq!(interpreter.run(&path, "print \"a\\qb\""), Err(E::Escape("\\q".to_string())));
+        assert_eq!(interpreter.context.trace.last().map(|(pos, _)| pos.clone()), Some(Position::new(0..0, 8..10)));
+        assert_eq!(interpreter.run(&path, "print 9223372036854775808"), Err(E::NumberRange("9223372036854775808".to_string())));
+        assert_eq!(interpreter.run(&path, "print 0x"), Err(E::Number("0x".to_string())));
+        assert_eq!(interpreter.run(&path, "print 1_"), Err(E::Number("1_".to_string())));
+        assert_eq!(interpreter.run(&path, "print 1 $( $( )$"), Err(E::UnclosedComment));
+        let Err(E::Errors(errors)) = interpreter.run(&path, "print 1);\nprint [1 }; print 2;\nprint (") else { panic!() };
+        let errors: Vec<E> = errors.into_iter().map(|(e, _)| e).collect();
+        assert_eq!(errors, vec![
+            E::UnexpectedToken(lexer::T::EvalOut),
+            E::UnexpectedToken(lexer::T::BodyOut),
+            E::Unclosed(lexer::T::EvalIn)
+        ]);
+    }
+    #[test]
+    fn diagnostics() {
+        let path = "<test>".to_string();
+        let mut interpreter = Interpreter::new(&path);
+        let e = interpreter.run(&path, "def @double (function <int> #(* %0 2));\n\tdouble \"two\"").unwrap_err();
+        assert_eq!(interpreter.display(&e), [
+            "ERROR[F0006]: expected type int but got type str",
+            " --> <test>:2:9",
+            "  |",
+            "2 | \tdouble \"two\"",
+            "  | \t       ^^^^^",
+            "note: pattern declared here",
+            " --> <test>:1:23",
+            "  |",
+            "1 | def @double (function <int> #(* %0 2));",
+            "  |                       -----",
+            "backtrace:",
+            "  0: double at <test>:2:2",
+            ""
+        ].join("\n"));
+        interpreter.color = true;
+        assert!(interpreter.display(&e).starts_with("\x1b[1;31mERROR[F0006]\x1b[0m"));
+        interpreter.error_format = ErrorFormat::Json;
+        assert_eq!(interpreter.display(&e), concat!(
+            r#"{"code":"F0006","kind":"expected-type","message":"expected type int but got type str","#,
+            r#""file":"<test>","line_start":2,"line_end":2,"column_start":9,"column_end":14,"#,
+            r#""trace":[{"file":"<test>","line_start":2,"line_end":2,"column_start":9,"column_end":14}],"#,
+            r#""notes":[{"message":"pattern declared here","file":"<test>","line_start":1,"line_end":1,"column_start":23,"column_end":28}],"#,
+            r#""backtrace":[{"name":"double","file":"<test>","line_start":2,"line_end":2,"column_start":2,"column_end":14}]}"#
+        ));
+        interpreter.error_format = ErrorFormat::Human;
+        interpreter.color = false;
+        let e = interpreter.run(&path, "print 1;\nprint (+ 1 \"a\")").unwrap_err();
+        assert_eq!(interpreter.display(&e), [
+            "ERROR[F0010]: illegal operation between type int and type str",
+            " --> <test>:2:12",
+            "  |",
+            "2 | print (+ 1 \"a\")",
+            "  |            ^^^",
+            ""
+        ].join("\n"));
+    }
+    #[test]
+    fn vm() -> Result<(), E> {
+        let mut paths: Vec<PathBuf> = fs::read_dir("tests").unwrap().map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "funx")).collect();
+        paths.sort();
+        for path in paths {
+            let mut interpreter = Interpreter::new(path.to_string_lossy().as_ref());
+            interpreter.context.backend = Backend::Vm;
+            interpreter.runfile_core(path.to_string_lossy().as_ref())?;
+        }
+        let path = "<test>".to_string();
+        let text = "def @f (function <int> #(if (lt %0 0) #(return (- 0 %0)) #(+ %0 [1 2])));\nassert (= (f -1) 1); f 1".to_string();
+        let mut tree = Interpreter::with_core(&path)?;
+        let mut vm = Interpreter::with_core(&path)?;
+        vm.context.backend = Backend::Vm;
+        let (e1, e2) = (tree.run(&path, &text).unwrap_err(), vm.run(&path, &text).unwrap_err());
+        assert_eq!(e1, e2);
+        assert_eq!(tree.display(&e1), vm.display(&e2));
+        let tokens = vm.lex("+ 1 2")?;
+        let node = vm.parse(&tokens)?;
+        assert_eq!(vm.get(&node)?.0, V::Int(3));
+        vm.run(&path, "var @g #(+ %0 1); g 1")?;
+        let node = match vm.get_var("g") {
+            Some(V::Closure(node, _, _)) => std::rc::Rc::downgrade(&node),
+            _ => panic!()
+        };
+        assert!(node.upgrade().is_some_and(|node| node.chunk.get().is_some()));
+        std::mem::drop(vm);
+        assert!(node.upgrade().is_none());
+        Ok(())
+    }
+    #[test]
+    fn resolver() -> Result<(), E> {
+        fn words(node: &parser::Node, found: &mut Vec<parser::N>) {
+            match &node.0 {
+                parser::N::Eval(nodes) | parser::N::Body(nodes) => for n in nodes { words(n, found) },
+                parser::N::Closure(n) => words(&n.node, found),
+                n @ (parser::N::Word(_) | parser::N::Prelude(..) | parser::N::Local(..)) => found.push(n.clone()),
+                _ => {}
+            }
+        }
+        let mut interpreter = Interpreter::with_core("<test>")?;
+        interpreter.run("<test>", "var @a 1")?;
+        let tokens = interpreter.lex("#{ var @b 2; if true #(var @inc 3) #{}; for @i 0 #(lt i b) #(inc! @i) #(+ a b inc i) }")?;
+        let node = resolver::resolve(interpreter.parse(&tokens)?, &interpreter.context);
+        let mut found = vec![];
+        words(&node, &mut found);
+        let slot = |word: &str| interpreter.context.prelude.slot(&word.to_string()).unwrap();
+        assert_eq!(found, vec![
+            parser::N::Prelude("var".to_string(), slot("var")),
+            parser::N::Prelude("if".to_string(), slot("if")), parser::N::Prelude("var".to_string(), slot("var")),
+            parser::N::Prelude("for".to_string(), slot("for")),
+            parser::N::Prelude("lt".to_string(), slot("lt")), parser::N::Local("i".to_string(), 0, 0), parser::N::Local("b".to_string(), 1, 0),
+            parser::N::Prelude("inc!".to_string(), slot("inc!")),
+            parser::N::Prelude("+".to_string(), slot("+")), parser::N::Local("a".to_string(), 2, 0), parser::N::Local("b".to_string(), 1, 0),
+            parser::N::Local("inc".to_string(), 1, 1), parser::N::Local("i".to_string(), 0, 0)
+        ]);
+        Ok(())
+    }
+    #[test]
+    fn stack_overflow() -> Result<(), E> {
+        let path = "<test>".to_string();
+        let mut interpreter = Interpreter::with_core(&path)?;
+        interpreter.context.max_depth = 10;
+        let e = interpreter.run(&path, "def @r (function <int> #(+ 1 (r %0)));\nr 1").unwrap_err();
+        assert_eq!(e, E::StackOverflow(10));
+        assert_eq!(interpreter.context.trace.last().map(|(pos, _)| pos.clone()), Some(Position::new(0..0, 29..35)));
+        assert!(interpreter.display(&e).ends_with(&[
+            "backtrace:",
+            "  0: r at <test>:1:30",
+            "     ... repeated 8 more times",
+            "  9: r at <test>:2:1",
+            ""
+        ].join("\n")));
+        let (value, _) = interpreter.run(&path, "error-kind (try #(r 1))")?;
+        assert_eq!(value, V::String("stack-overflow".to_string()));
+        assert!(interpreter.context.stack.is_empty());
+        interpreter.context.max_depth = 1000;
+        interpreter.context.max_stack = 1024;
+        let e = interpreter.run(&path, "r 1").unwrap_err();
+        assert_eq!(e, E::StackSize(1024));
+        assert!(interpreter.display(&e).starts_with("ERROR[F0029]: calls took more than 1024 bytes of stack"));
+        assert!(interpreter.context.stack.is_empty());
+        Ok(())
+    }
+    #[test]
+    fn stack_overflow_default() {
+        // a thread with the stack size threads get by default
+        let thread = std::thread::Builder::new().stack_size(2 * 1024 * 1024).spawn(|| {
+            let path = "<test>".to_string();
+            for backend in [Backend::Tree, Backend::Vm] {
+                let mut interpreter = Interpreter::with_core(&path).unwrap();
+                interpreter.context.backend = backend;
+                let e = interpreter.run(&path, "def @r (function <int> #(+ 1 (r %0)));\nr 1").unwrap_err();
+                assert!(matches!(e, E::StackOverflow(_) | E::StackSize(_)));
+                assert_eq!(interpreter.run(&path, "r 1"), Err(e));
+                assert!(interpreter.context.stack.is_empty());
+            }
+        }).unwrap();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn fuel() -> Result<(), E> {
+        let path = "<test>".to_string();
+        let text = "def @f (function <int> #{ if (le %0 0) #(return <int>); var @v [%0 &{ @k (f (- %0 1)) }]; return (f (- %0 1)) });\n\
+            print (f 3) (try #(throw 1) #{ return 2 }); $ 2 nodes".to_string();
+        let mut left = vec![];
+        for backend in [Backend::Tree, Backend::Vm] {
+            let mut interpreter = Interpreter::with_core(&path)?;
+            interpreter.context.backend = backend;
+            interpreter.set_fuel(Some(1000));
+            assert_eq!(interpreter.run(&path, "while true #{}"), Err(E::OutOfFuel));
+            assert_eq!(interpreter.fuel(), Some(0));
+            assert_eq!(interpreter.context.trace.last().map(|(pos, _)| pos.clone()), Some(Position::new(0..0, 12..14)));
+            interpreter.add_fuel(100);
+            assert_eq!(interpreter.run(&path, "try #(while true #{}) #(print 1)"), Err(E::OutOfFuel));
+            interpreter.set_fuel(Some(4));
+            assert_eq!(interpreter.run(&path, "+ 1 2")?.0, V::Int(3));
+            assert_eq!(interpreter.fuel(), Some(0));
+            interpreter.set_fuel(Some(10000));
+            interpreter.run(&path, &text)?;
+            left.push(interpreter.fuel());
+            interpreter.set_fuel(None);
+            interpreter.run(&path, "while #(lt 0 -1) #{}")?;
+        }
+        assert_eq!(left[0], left[1]);
+        Ok(())
+    }
+    #[test]
+    fn drop() -> Result<(), E> {
+        let path = "<test>".to_string();
+        let mut interpreter = Interpreter::with_core(&path)?;
+        interpreter.run(&path, "def @f (function <> #(f)); var @g #(g); var @m &{@h #(h)}")?;
+        let core = match interpreter.get_var("inc") {
+            Some(V::Function(_, value, _)) => match *value {
+                V::Closure(_, _, env) => std::rc::Rc::downgrade(&env.global),
+                _ => panic!()
+            }
+            _ => panic!()
+        };
+        let global = std::rc::Rc::downgrade(&interpreter.context.global);
+        let scope = std::rc::Rc::downgrade(&interpreter.context.scopes[0]);
+        std::mem::drop(interpreter);
+        assert!(core.upgrade().is_none() && global.upgrade().is_none() && scope.upgrade().is_none());
+        Ok(())
+    }
+    #[test]
+    fn search_path() -> Result<(), E> {
+        let mut interpreter = Interpreter::with_core("<test>")?;
+        assert!(interpreter.run("<test>", "import \"math.funx\"").is_err());
+        interpreter.add_search_path("tests/modules");
+        interpreter.run("<test>", "def @m (import \"math.funx\")")?;
+        let (value, _) = interpreter.run("<test>", "m.cube 3")?;
+        assert_eq!(value, V::Int(27));
+        Ok(())
+    }
+    #[test]
+    fn native_closure() -> Result<(), E> {
+        use std::{rc::Rc, cell::Cell};
+        let counter = Rc::new(Cell::new(0));
+        let mut interpreter = Interpreter::new("<test>");
+        let _counter = counter.clone();
+        interpreter.register_native("tick", Some(vec![Type::Int]), move |args, _, _, _| {
+            if let V::Int(v) = args[0] { _counter.set(_counter.get() + v) }
+            Ok((V::Int(_counter.get()), R::None))
+        })?;
+        interpreter.run("<test>", "tick 2; tick 3; tick 4")?;
+        assert_eq!(counter.get(), 9);
+        assert!(interpreter.register_native("tick", None, |_, _, _, _| Ok((V::Null, R::None))).is_err());
+        Ok(())
+    }
+}
@@ -1,79 +1,78 @@
-#![allow(unused_variables)]
-#![allow(dead_code)]
+mod repl;
+use funx::{Interpreter, ErrorFormat, Backend, CORE_PATH};
 
-mod position;
-mod error;
-mod values;
-mod context;
-mod lexer;
-mod parser;
-mod evaluator;
-use error::*;
-use values::*;
-use context::*;
-use evaluator::*;
+use std::{env, process, thread};
+use std::io::{self, IsTerminal};
 
-use std::{env, fs};
+static USAGE: &str = "usage: funx [--path <dir>]... [--plain] [--error-format=human|json] [--backend=tree|vm] [--doc] [file]";
 
-pub fn run(path: &String, text: &String, context: &mut Context) -> Result<(V, R), E> {
-    let tokens = lexer::lex(&text)?;
-    // println!("{tokens:?}");
-    if tokens.len() == 0 { return Ok((V::Null, R::None)) }
-    
-    let node = parser::parse(&tokens, context)?;
-    // println!("{node}");
-    
-    evaluator::get(&node, context)
-}
-pub fn runfile(path: &String, context: &mut Context) -> Result<(V, R), E> {
-    let res = fs::read_to_string(path);
-    if res.is_err() { return Err(E::FileNotFound(path.clone())) }
-    let text = res.unwrap();
-    run(path, &text, context)
-}
-pub fn runfile_core(path: &String) -> Result<(V, R), E>{
-    let mut context = funx_context(&path);
-    context.path = "std/core.funx".to_string();
-    runfile(&"std/core.funx".to_string(), &mut context)?;
-    context.path = path.clone();
-    runfile(&path, &mut context)
+/// Prints the doc comments of the file at `path` as Markdown and returns the exit code.
+fn doc(interpreter: &mut Interpreter, path: &String) -> i32 {
+    match interpreter.docs(path) {
+        Ok(docs) => {
+            println!("# {path}");
+            for (word, doc) in docs { println!("\n## `{word}`\n{doc}"); }
+            0
+        }
+        Err(e) => { eprintln!("{}", interpreter.display(&e)); 1 }
+    }
 }
 
+/// The interpreter runs on a thread with this much stack and lets calls take half of it,
+/// which leaves room for the default maximum call depth.
+static STACK_SIZE: usize = 256 * 1024 * 1024;
+
 fn main () {
+    let interpreter = thread::Builder::new().stack_size(STACK_SIZE).spawn(run).unwrap();
+    process::exit(interpreter.join().unwrap_or(101));
+}
+
+/// Runs the command line and returns the exit code: 1 if the script failed and 2 for wrong arguments.
+fn run() -> i32 {
     let mut args = env::args();
     args.next();
-    let input_path = args.next();
-    match input_path {
-        None => {},
-        Some(path) => {
-            let mut context = funx_context(&path);
-            context.path = "std/core.funx".to_string();
-            let res = runfile(&"std/core.funx".to_string(), &mut context);
-            if res.is_err() { println!("{}", res.err().unwrap().display(&context)); return }
-            context.path = path.clone();
-            let res = runfile(&path, &mut context);
-            if res.is_err() { println!("{}", res.err().unwrap().display(&context)); return }
+    let mut input_path: Option<String> = None;
+    let mut search_path: Vec<String> = vec![];
+    let mut color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let mut error_format = ErrorFormat::Human;
+    let mut backend = Backend::Tree;
+    let mut docs = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--path" | "-I" => match args.next() {
+                Some(dir) => search_path.push(dir),
+                None => { eprintln!("{USAGE}"); return 2 }
+            }
+            "--plain" => color = false,
+            "--doc" => docs = true,
+            _ if arg.starts_with("--error-format=") => match arg["--error-format=".len()..].parse() {
+                Ok(format) => error_format = format,
+                Err(()) => { eprintln!("{USAGE}"); return 2 }
+            }
+            _ if arg.starts_with("--backend=") => match arg["--backend=".len()..].parse() {
+                Ok(b) => backend = b,
+                Err(()) => { eprintln!("{USAGE}"); return 2 }
+            }
+            _ if arg.starts_with("--path=") => search_path.push(arg["--path=".len()..].to_string()),
+            _ if arg.starts_with('-') => { eprintln!("{USAGE}"); return 2 }
+            _ => input_path = Some(arg)
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::*;
-    #[test]
-    fn inc() -> Result<(), E> {
-        runfile_core(&"tests/inc.funx".to_string())?; Ok(())
-    }
-    #[test]
-    fn not() -> Result<(), E> {
-        runfile_core(&"tests/bool.funx".to_string())?; Ok(())
-    }
-    #[test]
-    fn types() -> Result<(), E> {
-        runfile_core(&"tests/types.funx".to_string())?; Ok(())
+    let mut interpreter = Interpreter::new(&input_path.clone().unwrap_or(repl::REPL_PATH.to_string()));
+    interpreter.color = color;
+    interpreter.error_format = error_format;
+    interpreter.context.backend = backend;
+    interpreter.context.max_stack = STACK_SIZE / 2;
+    interpreter.add_search_path_env("FUNX_PATH");
+    for dir in search_path.iter() { interpreter.add_search_path(dir); }
+    if docs {
+        return doc(&mut interpreter, &input_path.unwrap_or(CORE_PATH.to_string()))
     }
-    #[test]
-    fn comp() -> Result<(), E> {
-        runfile_core(&"tests/comp.funx".to_string())?; Ok(())
+    match input_path {
+        None => { repl::repl(interpreter); 0 }
+        Some(path) => match interpreter.runfile_core(&path) {
+            Ok(_) => 0,
+            Err(e) => { eprintln!("{}", interpreter.display(&e)); 1 }
+        }
     }
-}
\ No newline at end of file
+}
@@ -3,12 +3,19 @@ use crate::error::*;
 use crate::values::*;
 use crate::context::*;
 use crate::lexer::*;
+use crate::vm::Chunk;
+use std::rc::Rc;
+use std::cell::OnceCell;
 
 #[derive(Clone, PartialEq)]
 pub enum N {
-    Eval(Vec<Node>), Body(Vec<Node>), Pattern(Vec<Node>), Vector(Vec<Node>),
-    Addr(Box<Node>), Arg(Box<Node>), Closure(Box<Node>),
-    Null, Wirldcard, Word(String), Int(i64), Float(f64), Bool(bool), String(String), Type(Type)
+    Eval(Vec<Node>), Body(Vec<Node>), Pattern(Vec<Node>), Vector(Vec<Node>), Map(Vec<(Node, Node)>),
+    Addr(Box<Node>), Arg(Box<Node>), Closure(Rc<ClosureNode>),
+    Null, Wirldcard, Word(String), Int(i64), Float(f64), Bool(bool), String(String), Type(Type),
+    /// A word found in slot `usize` of the prelude by the resolver, see [`crate::resolver`].
+    Prelude(String, usize),
+    /// A variable the resolver found in a scope that many scopes out, in the slot, see [`crate::resolver`].
+    Local(String, usize, usize)
 }
 impl std::fmt::Debug for N {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -17,12 +24,13 @@ impl std::fmt::Debug for N {
             Self::Body(nodes) => write!(f, "{{{}}}", nodes.iter().map(|x| x.to_string()).collect::<Vec<String>>().join("; ")),
             Self::Pattern(nodes) => write!(f, "<{}>", nodes.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" ")),
             Self::Vector(nodes) => write!(f, "[{}]", nodes.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" ")),
+            Self::Map(entries) => write!(f, "&{{{}}}", entries.iter().map(|(k, v)| format!("{k} {v}")).collect::<Vec<String>>().join("; ")),
             Self::Addr(node) => write!(f, "@{node}"),
             Self::Arg(node) => write!(f, "%{node}"),
             Self::Closure(node) => write!(f, "#{node}"),
             Self::Null => write!(f, "null"),
             Self::Wirldcard => write!(f, "_"),
-            Self::Word(v) => write!(f, "{v}"),
+            Self::Word(v) | Self::Prelude(v, _) | Self::Local(v, ..) => write!(f, "{v}"),
             Self::Int(v) => write!(f, "{v}"),
             Self::Float(v) => write!(f, "{v}"),
             Self::Bool(v) => write!(f, "{v}"),
@@ -49,12 +57,39 @@ impl std::fmt::Display for Node {
     }
 }
 
+/// The node of a closure, shared by every closure value made from it. The VM keeps the chunk it compiles
+/// the node to next to it, so the node is only compiled once and the chunk is freed together with it.
+#[derive(Clone)]
+pub struct ClosureNode {
+    pub node: Node,
+    pub chunk: OnceCell<Chunk>
+}
+impl ClosureNode {
+    pub fn new(node: Node) -> Self { Self { node, chunk: OnceCell::new() } }
+}
+impl PartialEq for ClosureNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+impl std::fmt::Debug for ClosureNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.node)
+    }
+}
+impl std::fmt::Display for ClosureNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.node)
+    }
+}
+
 pub struct Parser {
     pub tokens: Vec<Token>,
-    pub idx: usize
+    pub idx: usize,
+    pub errors: Vec<(E, Vec<(Position, String)>)>
 }
 impl Parser {
-    pub fn new(tokens: &Vec<Token>) -> Self { Self { tokens: tokens.clone(), idx: 0 } }
+    pub fn new(tokens: &[Token]) -> Self { Self { tokens: tokens.to_vec(), idx: 0, errors: vec![] } }
     pub fn advance(&mut self) { self.idx += 1; }
     pub fn token_pos(&self) -> &Token {
         if self.idx >= self.tokens.len() { return &Token(T::NO, Position(0..0, 0..0)) }
@@ -68,26 +103,71 @@ impl Parser {
         if self.idx >= self.tokens.len() { return &self.tokens.last().unwrap().1 }
         &self.tokens[self.idx].1
     }
-    pub fn parse(&mut self, context: &mut Context) -> Result<Node, E> {
-        let start = self.pos().clone();
+    /// Remembers a syntax error together with its trace and skips to the start of the next statement,
+    /// so the rest of the input still gets parsed. In a body the closing `}` is left for the body.
+    pub fn recover(&mut self, e: E, trace: usize, in_body: bool, context: &mut Context) {
+        self.errors.push((e, context.trace.split_off(trace)));
+        let mut depth = 0;
+        loop {
+            match self.token() {
+                T::NO => break,
+                T::End if depth == 0 => { self.advance(); break }
+                T::BodyOut if depth == 0 && in_body => break,
+                T::EvalIn | T::BodyIn | T::PattIn | T::VecIn => depth += 1,
+                T::EvalOut | T::BodyOut | T::PattOut | T::VecOut if depth > 0 => depth -= 1,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+    /// Parses the statements of a body up to `end`, recovering from errors in single statements.
+    pub fn statements(&mut self, end: &T, context: &mut Context) -> Vec<Node> {
         let mut body_nodes: Vec<Node> = vec![];
-        while self.token() != &T::NO {
+        while self.token() != end && self.token() != &T::NO {
             let start_node = self.pos().clone();
+            let trace = context.trace.len();
             let mut nodes: Vec<Node> = vec![];
-            while self.token() != &T::End && self.token() != &T::NO {
-                let node = self.next(context)?;
-                nodes.push(node);
+            let mut res = Ok(());
+            while self.token() != &T::End && self.token() != end && self.token() != &T::NO {
+                match self.next(context) {
+                    Ok(node) => nodes.push(node),
+                    Err(e) => { res = Err(e); break }
+                }
+            }
+            if let Err(e) = res {
+                self.recover(e, trace, end == &T::BodyOut, context);
+                continue
             }
             if self.token() == &T::End { self.advance(); }
+            let stop = nodes.last().map(|node| node.1.clone()).unwrap_or(start_node.clone());
             body_nodes.push(Node(
                 N::Eval(nodes),
-                Position::new(start_node.0.start..self.pos().0.end, start_node.1.start..self.pos().1.end)
+                Position::new(start_node.0.start..stop.0.end, start_node.1.start..stop.1.end)
             ));
         }
+        body_nodes
+    }
+    pub fn parse(&mut self, context: &mut Context) -> Result<Node, E> {
+        let start = self.pos().clone();
+        let body_nodes = self.statements(&T::NO, context);
+        if self.errors.len() == 1 {
+            let (e, trace) = self.errors.pop().unwrap();
+            context.trace.extend(trace);
+            return Err(e)
+        }
+        if self.errors.len() > 1 {
+            return Err(E::Errors(std::mem::take(&mut self.errors)))
+        }
         if body_nodes.len() == 1 {
             return Ok(body_nodes[0].clone())
         }
-        return Ok(Node(N::Body(body_nodes), Position::new(start.0.start..self.pos().0.end, start.1.start..self.pos().1.end)))
+        let stop = body_nodes.last().map(|node| node.1.clone()).unwrap_or(start.clone());
+        Ok(Node(N::Body(body_nodes), Position::new(start.0.start..stop.0.end, start.1.start..stop.1.end)))
+    }
+    /// The error for an input that ends before the bracket opened at `start` is closed.
+    fn unclosed(&self, open: T, start: &Position, context: &mut Context) -> E {
+        context.trace(start);
+        E::Unclosed(open)
     }
     pub fn next(&mut self, context: &mut Context) -> Result<Node, E> {
         let start = self.pos().clone();
@@ -95,50 +175,67 @@ impl Parser {
             self.advance();
             let mut nodes: Vec<Node> = vec![];
             while self.token() != &T::EvalOut {
+                if self.token() == &T::NO { return Err(self.unclosed(T::EvalIn, &start, context)) }
                 let node = self.next(context)?;
                 nodes.push(node);
             }
+            let stop = self.pos().clone();
             self.advance();
-            return Ok(Node(N::Eval(nodes), Position::new(start.0.start..self.pos().0.end, start.1.start..self.pos().1.end)))
+            return Ok(Node(N::Eval(nodes), Position::new(start.0.start..stop.0.end, start.1.start..stop.1.end)))
         }
         if self.token() == &T::BodyIn {
             self.advance();
-            let mut body_nodes: Vec<Node> = vec![];
-            while self.token() != &T::BodyOut {
-                let start_node = self.pos().clone();
-                let mut nodes: Vec<Node> = vec![];
-                while self.token() != &T::End && self.token() != &T::BodyOut {
-                    let node = self.next(context)?;
-                    nodes.push(node);
-                }
-                if self.token() == &T::End { self.advance(); }
-                body_nodes.push(Node(
-                    N::Eval(nodes),
-                    Position::new(start_node.0.start..self.pos().0.end, start_node.1.start..self.pos().1.end)
-                ));
-            }
+            let body_nodes = self.statements(&T::BodyOut, context);
+            if self.token() == &T::NO { return Err(self.unclosed(T::BodyIn, &start, context)) }
+            let stop = self.pos().clone();
             self.advance();
-            return Ok(Node(N::Body(body_nodes), Position::new(start.0.start..self.pos().0.end, start.1.start..self.pos().1.end)))
+            return Ok(Node(N::Body(body_nodes), Position::new(start.0.start..stop.0.end, start.1.start..stop.1.end)))
         }
         if self.token() == &T::PattIn {
             self.advance();
             let mut nodes: Vec<Node> = vec![];
             while self.token() != &T::PattOut {
+                if self.token() == &T::NO { return Err(self.unclosed(T::PattIn, &start, context)) }
                 let node = self.next(context)?;
                 nodes.push(node);
             }
+            let stop = self.pos().clone();
             self.advance();
-            return Ok(Node(N::Pattern(nodes), Position::new(start.0.start..self.pos().0.end, start.1.start..self.pos().1.end)))
+            return Ok(Node(N::Pattern(nodes), Position::new(start.0.start..stop.0.end, start.1.start..stop.1.end)))
         }
         if self.token() == &T::VecIn {
             self.advance();
             let mut nodes: Vec<Node> = vec![];
             while self.token() != &T::VecOut {
+                if self.token() == &T::NO { return Err(self.unclosed(T::VecIn, &start, context)) }
                 let node = self.next(context)?;
                 nodes.push(node);
             }
+            let stop = self.pos().clone();
+            self.advance();
+            return Ok(Node(N::Vector(nodes), Position::new(start.0.start..stop.0.end, start.1.start..stop.1.end)))
+        }
+        if self.token() == &T::Map {
             self.advance();
-            return Ok(Node(N::Vector(nodes), Position::new(start.0.start..self.pos().0.end, start.1.start..self.pos().1.end)))
+            if self.token() != &T::BodyIn {
+                context.trace(self.pos());
+                return Err(E::UnexpectedToken(self.token().clone()))
+            }
+            let body = self.next(context)?;
+            let mut entries: Vec<(Node, Node)> = vec![];
+            if let N::Body(nodes) = &body.0 {
+                for n in nodes {
+                    if let N::Eval(entry) = &n.0 {
+                        if entry.is_empty() { continue }
+                        if entry.len() != 2 {
+                            context.trace(&n.1);
+                            return Err(E::MapEntry(entry.len()))
+                        }
+                        entries.push((entry[0].clone(), entry[1].clone()));
+                    }
+                }
+            }
+            return Ok(Node(N::Map(entries), Position::new(start.0.start..body.1.0.end, start.1.start..body.1.1.end)))
         }
         if self.token() == &T::Addr {
             self.advance();
@@ -156,20 +253,20 @@ impl Parser {
             self.advance();
             let node = self.next(context)?;
             let pos = node.1.clone();
-            return Ok(Node(N::Closure(Box::new(node)), Position::new(start.0.start..pos.0.end, start.1.start..pos.1.end)))
+            return Ok(Node(N::Closure(Rc::new(ClosureNode::new(node))), Position::new(start.0.start..pos.0.end, start.1.start..pos.1.end)))
         }
         if let Token(T::Int(v), pos) = self.token_pos() {
-            let node = Ok(Node(N::Int(v.clone()), pos.clone()));
+            let node = Ok(Node(N::Int(*v), pos.clone()));
             self.advance();
             return node
         }
         if let Token(T::Float(v), pos) = self.token_pos() {
-            let node = Ok(Node(N::Float(v.clone()), pos.clone()));
+            let node = Ok(Node(N::Float(*v), pos.clone()));
             self.advance();
             return node
         }
         if let Token(T::Bool(v), pos) = self.token_pos() {
-            let node = Ok(Node(N::Bool(v.clone()), pos.clone()));
+            let node = Ok(Node(N::Bool(*v), pos.clone()));
             self.advance();
             return node
         }
@@ -203,7 +300,7 @@ impl Parser {
     }
 }
 
-pub fn parse(tokens: &Vec<Token>, context: &mut Context) -> Result<Node, E> {
+pub fn parse(tokens: &[Token], context: &mut Context) -> Result<Node, E> {
     let mut parser = Parser::new(tokens);
     parser.parse(context)
 }
\ No newline at end of file
@@ -0,0 +1,70 @@
+use funx::{Interpreter, V, E, lexer::{T, Token}};
+
+use std::io::{self, BufRead, Write};
+
+pub static REPL_PATH: &str = "<repl>";
+
+/// How many brackets are still open after `tokens`.
+pub fn depth(tokens: &Vec<Token>) -> isize {
+    let mut depth: isize = 0;
+    for Token(token, _) in tokens {
+        match token {
+            T::EvalIn | T::BodyIn | T::PattIn | T::VecIn => depth += 1,
+            T::EvalOut | T::BodyOut | T::PattOut | T::VecOut => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// Prints the doc comment of `word`, or all documented words if there is no word.
+fn help(interpreter: &Interpreter, word: &str) {
+    if word.is_empty() {
+        println!("documented words: {}", interpreter.context.documented().join(" "));
+        return
+    }
+    match interpreter.doc(word) {
+        Some(doc) => println!("{doc}"),
+        None if interpreter.get_var(word).is_some() => println!("{word} has no documentation"),
+        None => println!("{word} is not defined")
+    }
+}
+
+fn prompt(text: &str) {
+    print!("{text}");
+    let _ = io::stdout().flush();
+}
+
+pub fn repl(mut interpreter: Interpreter) {
+    let path = REPL_PATH.to_string();
+    if let Err(e) = interpreter.load_core() { println!("{}", interpreter.display(&e)); return }
+    let stdin = io::stdin();
+    let mut input = String::new();
+    prompt("> ");
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else { break };
+        if input.is_empty() && (line.trim() == "help" || line.trim_start().starts_with("help ")) {
+            help(&interpreter, line.trim()["help".len()..].trim());
+            prompt("> ");
+            continue
+        }
+        input.push_str(&line);
+        input.push('\n');
+        match interpreter.lex(&input) {
+            Ok(tokens) if depth(&tokens) > 0 => { prompt(". "); continue }
+            Err(E::UnclosedString | E::UnclosedComment) => { prompt(". "); continue }
+            _ => {}
+        }
+        match interpreter.run(&path, &input) {
+            Ok((V::Null, _)) => {}
+            Ok((value, _)) => println!("{value}"),
+            Err(e) => {
+                println!("{}", interpreter.display(&e));
+                interpreter.context.scopes.truncate(1);
+            }
+        }
+        input.clear();
+        prompt("> ");
+    }
+    println!();
+}
@@ -0,0 +1,141 @@
+use crate::context::*;
+use crate::parser::*;
+use std::rc::Rc;
+
+/// Turns the words of `node` into references that are followed without searching the scopes by name:
+/// a variable a `var` of a surrounding scope defines becomes an [`N::Local`] holding how many scopes out it is
+/// and its slot there, and a word of the prelude becomes an [`N::Prelude`] holding its slot.
+/// Words the global scope defines are left alone since they take precedence over the prelude,
+/// and words behind `@` stay as they are because they are names, not references.
+///
+/// Scopes are laid out the way calls create them: a closure gets a scope of its own, except the closures
+/// `if` and `while` run in place and those of `for`, which share the scope of the loop variable.
+/// `var` can still define a variable where this cannot see it, e.g. only in the branch an `if` takes
+/// or under a computed name, so a variable that is not in its slot is looked up by name, see [`Context::get_local`].
+pub fn resolve(node: Node, context: &Context) -> Node {
+    let scopes = context.scopes.iter().map(|scope| scope.borrow().iter().map(|(word, _)| word.clone()).collect()).collect();
+    let mut resolver = Resolver { scopes, context };
+    resolver.declare(&node);
+    resolver.node(node)
+}
+
+struct Resolver<'a> {
+    /// The variables of the scopes around the node, innermost last, each in the order of their slots.
+    scopes: Vec<Vec<String>>,
+    context: &'a Context
+}
+impl Resolver<'_> {
+    fn node(&mut self, node: Node) -> Node {
+        let Node(n, pos) = node;
+        let n = match n {
+            N::Word(word) => self.word(word),
+            N::Eval(nodes) => N::Eval(self.eval(nodes)),
+            N::Body(nodes) => N::Body(self.nodes(nodes)),
+            N::Pattern(nodes) => N::Pattern(self.nodes(nodes)),
+            N::Vector(nodes) => N::Vector(self.nodes(nodes)),
+            N::Map(entries) => N::Map(entries.into_iter().map(|(k, v)| (self.node(k), self.node(v))).collect()),
+            N::Addr(n) if matches!(n.0, N::Word(_)) => N::Addr(n),
+            N::Addr(n) => N::Addr(Box::new(self.node(*n))),
+            N::Arg(n) => N::Arg(Box::new(self.node(*n))),
+            N::Closure(n) => N::Closure(self.scope(vec![], vec![n]).remove(0)),
+            n => n
+        };
+        Node(n, pos)
+    }
+    fn nodes(&mut self, nodes: Vec<Node>) -> Vec<Node> {
+        nodes.into_iter().map(|node| self.node(node)).collect()
+    }
+    fn word(&self, word: String) -> N {
+        if let Some((depth, slot)) = self.local(&word) { return N::Local(word, depth, slot) }
+        match self.prelude(&word) {
+            Some(slot) => N::Prelude(word, slot),
+            None => N::Word(word)
+        }
+    }
+    /// How many scopes out `word` is defined and its slot there.
+    fn local(&self, word: &String) -> Option<(usize, usize)> {
+        self.scopes.iter().rev().enumerate().find_map(|(depth, scope)| Some((depth, scope.iter().position(|name| name == word)?)))
+    }
+    /// The slot of `word` in the prelude, if no variable or global word hides it.
+    fn prelude(&self, word: &String) -> Option<usize> {
+        if self.local(word).is_some() || self.context.global.borrow().get(word).is_some() { return None }
+        self.context.prelude.slot(word)
+    }
+    /// The word of the prelude the call `nodes` makes if it runs closures in place, with the indices of those closures.
+    fn inline(&self, nodes: &[Node]) -> Option<(&'static str, &'static [usize])> {
+        let Some(Node(N::Word(word), _)) = nodes.first() else { return None };
+        let inline: (&'static str, &'static [usize]) = match word.as_str() {
+            "if" => ("if", &[2, 3]),
+            "while" => ("while", &[1, 2]),
+            // in the order they run
+            "for" => ("for", &[3, 5, 4]),
+            _ => return None
+        };
+        self.prelude(word).map(|_| inline)
+    }
+    fn eval(&mut self, nodes: Vec<Node>) -> Vec<Node> {
+        let Some((word, order)) = self.inline(&nodes) else { return self.nodes(nodes) };
+        let mut nodes = nodes;
+        let mut closures = vec![];
+        for &idx in order {
+            if let Some(Node(n @ N::Closure(_), _)) = nodes.get_mut(idx) {
+                let N::Closure(n) = std::mem::replace(n, N::Null) else { unreachable!() };
+                closures.push((idx, n));
+            }
+        }
+        let (idxs, ns): (Vec<usize>, Vec<Rc<ClosureNode>>) = closures.into_iter().unzip();
+        let ns = if word == "for" {
+            // the loop variable is the first variable of the scope the closures share
+            let var = match nodes.get(1) {
+                Some(Node(N::Addr(n), _)) => if let N::Word(var) = &n.0 { vec![var.clone()] } else { vec![] },
+                _ => vec![]
+            };
+            self.scope(var, ns)
+        } else {
+            ns.into_iter().map(|n| self.closure(n)).collect()
+        };
+        let mut nodes: Vec<Node> = nodes.into_iter().enumerate()
+            .map(|(idx, node)| if idxs.contains(&idx) { node } else { self.node(node) })
+            .collect();
+        for (idx, n) in idxs.into_iter().zip(ns) { nodes[idx].0 = N::Closure(n) }
+        nodes
+    }
+    /// Resolves the closures `ns` in a new scope starting with the variables `vars`.
+    fn scope(&mut self, vars: Vec<String>, ns: Vec<Rc<ClosureNode>>) -> Vec<Rc<ClosureNode>> {
+        self.scopes.push(vars);
+        for n in &ns { self.declare(&n.node) }
+        let ns = ns.into_iter().map(|n| self.closure(n)).collect();
+        self.scopes.pop();
+        ns
+    }
+    /// Resolves the closure `n` in the current scope.
+    fn closure(&mut self, n: Rc<ClosureNode>) -> Rc<ClosureNode> {
+        Rc::new(ClosureNode::new(self.node(Rc::unwrap_or_clone(n).node)))
+    }
+    /// Adds the variables a `var` in `node` defines to the innermost scope, in the order they are defined
+    /// when evaluating, which is the order of their slots. Closures run in place add theirs too.
+    fn declare(&mut self, node: &Node) {
+        match &node.0 {
+            N::Eval(nodes) => {
+                let Some((head, args)) = nodes.split_first() else { return };
+                let inline = self.inline(nodes);
+                for (idx, arg) in args.iter().enumerate() {
+                    match (&arg.0, inline) {
+                        (N::Closure(_), Some(("for", idxs))) if idxs.contains(&(idx + 1)) => {}
+                        (N::Closure(n), Some((_, idxs))) if idxs.contains(&(idx + 1)) => self.declare(&n.node),
+                        _ => self.declare(arg)
+                    }
+                }
+                self.declare(head);
+                let (N::Word(word), Some(N::Addr(addr))) = (&head.0, args.first().map(|arg| &arg.0)) else { return };
+                let N::Word(var) = &addr.0 else { return };
+                let scope = self.scopes.last_mut().unwrap();
+                if word == "var" && !scope.contains(var) { scope.push(var.clone()) }
+            }
+            N::Body(nodes) | N::Pattern(nodes) | N::Vector(nodes) => for n in nodes { self.declare(n) },
+            N::Map(entries) => for (k, v) in entries { self.declare(k); self.declare(v) },
+            N::Addr(n) | N::Arg(n) => self.declare(n),
+            _ => {}
+        }
+    }
+}
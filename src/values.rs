@@ -3,18 +3,25 @@ use crate::context::*;
 use crate::parser::*;
 use crate::evaluator::*;
 use crate::position::Position;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 
-pub type NativFunction = fn(Vec<V>, &mut Context, &Position, &Vec<&Position>) -> Result<(V, R), E>;
+pub type NativFunction = Rc<dyn Fn(Vec<V>, &mut Context, &Position, &[&Position]) -> Result<(V, R), E>>;
 
 #[derive(Clone)]
 pub enum Type {
-    Undefined, Any, Int, Float, Bool, String, Vector(Box<Type>), NativFunction, Function,
+    Undefined, Any, Int, Float, Bool, String, Vector(Box<Type>), Map, Error, Module, NativFunction, Function,
     Addr, Closure, Pattern,
     Union(Vec<Type>), Exclusion(Vec<Type>), Type
 }
 impl Type {
     pub fn some() -> Self { Self::Exclusion(vec![Type::Undefined]) }
     pub fn number() -> Self { Self::Union(vec![Type::Int, Type::Float]) }
+    pub fn key() -> Self { Self::Union(vec![Type::String, Type::Addr]) }
+    pub fn vector() -> Self { Self::Vector(Box::new(Type::Any)) }
+    pub fn callable() -> Self { Self::Union(vec![Type::Closure, Type::Function, Type::NativFunction]) }
+    pub fn option(typ: Type) -> Self { Self::Union(vec![typ, Type::Undefined]) }
     pub fn cast(&self, value: &V) -> V {
         match self {
             Self::Undefined => V::Null,
@@ -41,7 +48,31 @@ impl Type {
                 _ => V::Null
             }
             Self::String => V::String(value.to_string()),
-            Self::Addr => V::Addr(value.to_string()),
+            Self::Map => match value {
+                V::Null => V::Map(BTreeMap::new()),
+                V::Map(map) => V::Map(map.clone()),
+                V::Vector(pairs, _) => {
+                    let mut map = BTreeMap::new();
+                    for pair in pairs {
+                        if let V::Vector(pair, _) = pair {
+                            if pair.len() == 2 {
+                                if let Some(key) = pair[0].key() {
+                                    map.insert(key, pair[1].clone());
+                                    continue
+                                }
+                            }
+                        }
+                        return V::Null
+                    }
+                    V::Map(map)
+                }
+                _ => V::Null
+            }
+            Self::Error => match value {
+                V::Error(e, trace, backtrace) => V::Error(e.clone(), trace.clone(), backtrace.clone()),
+                _ => V::Error(Box::new(E::Thrown(value.clone())), vec![], vec![])
+            }
+            Self::Addr => V::Addr(value.to_string(), None),
             Self::Type => V::Type(value.typ()),
             _ => V::Null
         }
@@ -62,6 +93,9 @@ impl std::fmt::Debug for Type {
             Self::Bool => write!(f, "bool"),
             Self::String => write!(f, "str"),
             Self::Vector(typ) => write!(f, "vec<{typ}>"),
+            Self::Map => write!(f, "map"),
+            Self::Error => write!(f, "error"),
+            Self::Module => write!(f, "module"),
             Self::NativFunction => write!(f, "nativ-function"),
             Self::Function => write!(f, "function"),
             Self::Addr => write!(f, "addr"),
@@ -176,6 +210,9 @@ impl PartialEq for Type {
             (Self::Bool, Self::Bool) => true,
             (Self::String, Self::String) => true,
             (Self::Vector(typ1), Self::Vector(typ2)) => typ1 == typ2,
+            (Self::Map, Self::Map) => true,
+            (Self::Error, Self::Error) => true,
+            (Self::Module, Self::Module) => true,
             (Self::Addr, Self::Addr) => true,
             (Self::Closure, Self::Closure) => true,
             (Self::Pattern, Self::Pattern) => true,
@@ -189,9 +226,10 @@ impl PartialEq for Type {
 
 #[derive(Clone)]
 pub enum V {
-    Null, Wirldcard, Int(i64), Float(f64), Bool(bool), String(String), Vector(Vec<V>, Type),
-    Addr(String), Closure(Node, String), Pattern(Vec<Type>),
-    NativFunction(Box<V>, NativFunction), Function(Box<V>, Box<V>),
+    Null, Wirldcard, Int(i64), Float(f64), Bool(bool), String(String), Vector(Vec<V>, Type), Map(BTreeMap<String, V>),
+    Error(Box<E>, Vec<(Position, String)>, Vec<Frame>), Module(String, Rc<RefCell<Scope>>),
+    Addr(String, Option<Env>), Closure(Rc<ClosureNode>, String, Env), Pattern(Vec<Type>),
+    NativFunction(Box<V>, NativFunction), Function(Box<V>, Box<V>, (Position, String)),
     Type(Type)
 }
 impl V {
@@ -204,75 +242,133 @@ impl V {
             Self::Bool(_) => Type::Bool,
             Self::String(_) => Type::String,
             Self::Vector(_, typ) => Type::Vector(Box::new(typ.clone())),
-            Self::Addr(_) => Type::Addr,
-            Self::Closure(_, _) => Type::Closure,
+            Self::Map(_) => Type::Map,
+            Self::Error(_, _, _) => Type::Error,
+            Self::Module(_, _) => Type::Module,
+            Self::Addr(..) => Type::Addr,
+            Self::Closure(_, _, _) => Type::Closure,
             Self::Pattern(_) => Type::Pattern,
             Self::NativFunction(_, _) => Type::NativFunction,
-            Self::Function(_, _) => Type::Function,
+            Self::Function(_, _, _) => Type::Function,
             Self::Type(_) => Type::Type,
         }
     }
-    pub fn add(&self, other: &V) -> Option<V> {
+    /// Creates a vector whose element type is the union of the types of `values`.
+    pub fn vector(values: Vec<V>) -> V {
+        let mut types: Vec<Type> = vec![];
+        for value in values.iter() {
+            let typ = value.typ();
+            if !types.contains(&typ) { types.push(typ) }
+        }
+        let mut typ = Type::Any;
+        if !types.is_empty() {
+            typ = Type::Union(types);
+        }
+        V::Vector(values, typ)
+    }
+    /// The value named `name` inside of a module or map, as in `module.name`.
+    pub fn field(&self, name: &str) -> V {
         match self {
-            Self::Int(v1) => match other {
-                Self::Int(v2) => Some(V::Int(v1 + v2)),
-                Self::Float(v2) => Some(V::Float((*v1 as f64) + v2)),
-                _ => None
-            }
-            Self::Float(v1) => match other {
-                Self::Float(v2) => Some(V::Float(v1 + v2)),
-                Self::Int(v2) => Some(V::Float(v1 + (*v2 as f64))),
-                _ => None
-            }
-            Self::String(v1) => match other {
-                Self::String(v2) => Some(V::String(v1.to_owned() + v2)),
-                _ => None
-            }
-            _ => None
+            Self::Module(_, global) => global.borrow().get(&name.to_string()).cloned().unwrap_or(V::Null),
+            Self::Map(map) => map.get(name).cloned().unwrap_or(V::Null),
+            _ => V::Null
         }
     }
-    pub fn sub(&self, other: &V) -> Option<V> {
+    /// The string a value stands for when used as the key of a map.
+    pub fn key(&self) -> Option<String> {
         match self {
-            Self::Int(v1) => match other {
-                Self::Int(v2) => Some(V::Int(v1 - v2)),
-                Self::Float(v2) => Some(V::Float((*v1 as f64) - v2)),
-                _ => None
-            }
-            Self::Float(v1) => match other {
-                Self::Float(v2) => Some(V::Float(v1 - v2)),
-                Self::Int(v2) => Some(V::Float(v1 - (*v2 as f64))),
-                _ => None
-            }
+            Self::String(v) | Self::Addr(v, _) => Some(v.clone()),
             _ => None
         }
     }
-    pub fn mul(&self, other: &V) -> Option<V> {
-        match self {
-            Self::Int(v1) => match other {
-                Self::Int(v2) => Some(V::Int(v1 * v2)),
-                Self::Float(v2) => Some(V::Float((*v1 as f64) * v2)),
-                _ => None
+    fn binary(&self, other: &V) -> E {
+        E::BinaryOperation { type1: self.typ(), type2: other.typ() }
+    }
+    pub fn add(&self, other: &V) -> Result<V, E> {
+        match (self, other) {
+            (Self::Int(v1), Self::Int(v2)) => v1.checked_add(*v2).map(V::Int).ok_or(E::Overflow("+".to_string())),
+            (Self::Int(v1), Self::Float(v2)) => Ok(V::Float((*v1 as f64) + v2)),
+            (Self::Float(v1), Self::Float(v2)) => Ok(V::Float(v1 + v2)),
+            (Self::Float(v1), Self::Int(v2)) => Ok(V::Float(v1 + (*v2 as f64))),
+            (Self::String(v1), Self::String(v2)) => Ok(V::String(v1.to_owned() + v2)),
+            _ => Err(self.binary(other))
+        }
+    }
+    pub fn sub(&self, other: &V) -> Result<V, E> {
+        match (self, other) {
+            (Self::Int(v1), Self::Int(v2)) => v1.checked_sub(*v2).map(V::Int).ok_or(E::Overflow("-".to_string())),
+            (Self::Int(v1), Self::Float(v2)) => Ok(V::Float((*v1 as f64) - v2)),
+            (Self::Float(v1), Self::Float(v2)) => Ok(V::Float(v1 - v2)),
+            (Self::Float(v1), Self::Int(v2)) => Ok(V::Float(v1 - (*v2 as f64))),
+            _ => Err(self.binary(other))
+        }
+    }
+    pub fn mul(&self, other: &V) -> Result<V, E> {
+        match (self, other) {
+            (Self::Int(v1), Self::Int(v2)) => v1.checked_mul(*v2).map(V::Int).ok_or(E::Overflow("*".to_string())),
+            (Self::Int(v1), Self::Float(v2)) => Ok(V::Float((*v1 as f64) * v2)),
+            (Self::Float(v1), Self::Float(v2)) => Ok(V::Float(v1 * v2)),
+            (Self::Float(v1), Self::Int(v2)) => Ok(V::Float(v1 * (*v2 as f64))),
+            _ => Err(self.binary(other))
+        }
+    }
+    /// Division, which always results in a float.
+    pub fn div(&self, other: &V) -> Result<V, E> {
+        match (self.float(), other.float()) {
+            (Some(v1), Some(v2)) => Ok(V::Float(v1 / v2)),
+            _ => Err(self.binary(other))
+        }
+    }
+    /// Division rounded down to the next whole number, an int for two ints.
+    pub fn int_div(&self, other: &V) -> Result<V, E> {
+        match (self, other) {
+            (Self::Int(_), Self::Int(0)) => Err(E::DivisionByZero),
+            (Self::Int(v1), Self::Int(v2)) => {
+                let div = v1.checked_div(*v2).ok_or(E::Overflow("//".to_string()))?;
+                Ok(V::Int(if v1 % v2 != 0 && (*v1 < 0) != (*v2 < 0) { div - 1 } else { div }))
             }
-            Self::Float(v1) => match other {
-                Self::Float(v2) => Some(V::Float(v1 * v2)),
-                Self::Int(v2) => Some(V::Float(v1 * (*v2 as f64))),
-                _ => None
+            _ => match (self.float(), other.float()) {
+                (Some(v1), Some(v2)) => Ok(V::Float((v1 / v2).floor())),
+                _ => Err(self.binary(other))
             }
-            _ => None
         }
     }
-    pub fn div(&self, other: &V) -> Option<V> {
-        match self {
-            Self::Int(v1) => match other {
-                Self::Int(v2) => Some(V::Float((*v1 as f64) / (*v2 as f64))),
-                Self::Float(v2) => Some(V::Float((*v1 as f64) / v2)),
-                _ => None
+    /// The remainder of [`V::int_div`], which has the sign of the divisor.
+    pub fn rem(&self, other: &V) -> Result<V, E> {
+        match (self, other) {
+            (Self::Int(_), Self::Int(0)) => Err(E::DivisionByZero),
+            (Self::Int(v1), Self::Int(v2)) => {
+                let rem = v1.checked_rem(*v2).ok_or(E::Overflow("mod".to_string()))?;
+                Ok(V::Int(if rem != 0 && (rem < 0) != (*v2 < 0) { rem + v2 } else { rem }))
             }
-            Self::Float(v1) => match other {
-                Self::Float(v2) => Some(V::Float(v1 / v2)),
-                Self::Int(v2) => Some(V::Float(v1 / (*v2 as f64))),
-                _ => None
+            _ => match (self.float(), other.float()) {
+                (Some(v1), Some(v2)) => Ok(V::Float(v1 - v2 * (v1 / v2).floor())),
+                _ => Err(self.binary(other))
+            }
+        }
+    }
+    /// Power, an int for an int raised to a non-negative int.
+    pub fn pow(&self, other: &V) -> Result<V, E> {
+        match (self, other) {
+            (Self::Int(v1), Self::Int(v2)) if *v2 >= 0 => u32::try_from(*v2).ok().and_then(|v2| v1.checked_pow(v2))
+                .map(V::Int).ok_or(E::Overflow("pow".to_string())),
+            _ => match (self.float(), other.float()) {
+                (Some(v1), Some(v2)) => Ok(V::Float(v1.powf(v2))),
+                _ => Err(self.binary(other))
             }
+        }
+    }
+    pub fn abs(&self) -> Result<V, E> {
+        match self {
+            Self::Int(v) => v.checked_abs().map(V::Int).ok_or(E::Overflow("abs".to_string())),
+            Self::Float(v) => Ok(V::Float(v.abs())),
+            _ => Err(E::UnaryOperation(self.typ()))
+        }
+    }
+    fn float(&self) -> Option<f64> {
+        match self {
+            Self::Int(v) => Some(*v as f64),
+            Self::Float(v) => Some(*v),
             _ => None
         }
     }
@@ -322,11 +418,14 @@ impl std::fmt::Debug for V {
             Self::Bool(v) => write!(f, "{v}"),
             Self::String(v) => write!(f, "{v}"),
             Self::Vector(v, _) => write!(f, "[{}]", v.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" ")),
-            Self::Addr(v) => write!(f, "@{v}"),
-            Self::Closure(v, _) => write!(f, "#{v}"),
+            Self::Map(map) => write!(f, "&{{{}}}", map.iter().map(|(k, v)| format!("{k} {v}")).collect::<Vec<String>>().join("; ")),
+            Self::Error(e, _, _) => write!(f, "{e}"),
+            Self::Module(path, _) => write!(f, "module:{path}"),
+            Self::Addr(v, _) => write!(f, "@{v}"),
+            Self::Closure(v, _, _) => write!(f, "#{v}"),
             Self::Pattern(types) => write!(f, "<{}>", types.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" ")),
-            Self::NativFunction(_, v) => write!(f, "nativ-function:{:?}", v as *const NativFunction),
-            Self::Function(_, body) => write!(f, "function:{:?}", body as *const Box<V>),
+            Self::NativFunction(_, v) => write!(f, "nativ-function:{:?}", Rc::as_ptr(v) as *const ()),
+            Self::Function(_, body, _) => write!(f, "function:{:?}", body as *const Box<V>),
             Self::Type(typ) => write!(f, "{typ}"),
         }
     }
@@ -344,8 +443,11 @@ impl PartialEq for V {
             (Self::Bool(v1), Self::Bool(v2)) => v1 == v2,
             (Self::String(v1), Self::String(v2)) => v1 == v2,
             (Self::Vector(v1, _), Self::Vector(v2, _)) => v1 == v2,
-            (Self::Addr(v1), Self::Addr(v2)) => v1 == v2,
-            (Self::Closure(v1, _), Self::Closure(v2, _)) => v1 == v2,
+            (Self::Map(v1), Self::Map(v2)) => v1 == v2,
+            (Self::Error(e1, _, _), Self::Error(e2, _, _)) => e1 == e2,
+            (Self::Module(_, v1), Self::Module(_, v2)) => Rc::ptr_eq(v1, v2),
+            (Self::Addr(v1, _), Self::Addr(v2, _)) => v1 == v2,
+            (Self::Closure(v1, _, _), Self::Closure(v2, _, _)) => v1 == v2,
             (Self::Pattern(v1), Self::Pattern(v2)) => v1 == v2,
             (Self::Type(v1), Self::Type(v2)) => v1 == v2,
             _ => false
@@ -0,0 +1,389 @@
+use crate::position::*;
+use crate::error::*;
+use crate::values::*;
+use crate::context::*;
+use crate::parser::*;
+use crate::evaluator::*;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::collections::BTreeMap;
+
+/// Which way node trees are run: walked directly by [`crate::evaluator`] or compiled to a [`Chunk`] for the VM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend { Tree, Vm }
+impl FromStr for Backend {
+    type Err = ();
+    fn from_str(backend: &str) -> Result<Self, ()> {
+        match backend {
+            "tree" => Ok(Self::Tree),
+            "vm" => Ok(Self::Vm),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Takes a step of fuel for every node at the positions of the site, see [`Context::burn`].
+    Fuel(usize),
+    /// Pushes a constant of the chunk.
+    Const(usize),
+    /// Pushes the value of a word by its name.
+    Word(usize),
+    /// Pushes the value of a word from its slot in the prelude, see [`N::Prelude`].
+    Prelude(usize, usize),
+    /// Pushes the value of a variable from its slot in a scope that many scopes out, see [`N::Local`].
+    Local(usize, usize, usize),
+    /// Pushes an argument of the current call.
+    Arg(i64),
+    /// Pops an index and pushes the argument at it.
+    DynArg(usize),
+    /// Pushes the address of a word, capturing the current scopes.
+    Name(usize),
+    /// Pops a value and pushes it as an address, capturing the current scopes.
+    Addr(usize),
+    /// Pushes a closure of the node with the index, capturing the current scopes.
+    Closure(usize),
+    /// Fails unless the value on top is a type.
+    IsType(usize),
+    /// Fails unless the value below the top can be a map key.
+    IsKey(usize),
+    /// Pops that many types and pushes them as a pattern.
+    Pattern(usize),
+    /// Pops that many values and pushes them as a vector.
+    Vector(usize),
+    /// Pops a key and a value for that many entries and pushes them as a map.
+    Map(usize),
+    /// Pops the head and the arguments of the site and pushes the result of the call.
+    Call(usize),
+    /// Like `Call`, but in a called closure a call of a closure or function is left to the caller
+    /// of the closure to make, see [`Tail`].
+    TailCall(usize, Tail),
+    /// Like `Call`, but in a called closure the branch the `if` of the prelude takes runs right here,
+    /// in tail position if the `if` is.
+    If(usize, Option<Tail>),
+    Pop,
+    /// Until the matching `Uncatch`, a signal does not leave the chunk but is pushed as a value
+    /// and execution goes on at the index, like the tree walker ignores signals in patterns, addresses and arguments.
+    Catch(usize),
+    Uncatch,
+}
+
+/// Why a call is in tail position: it makes the value of the chunk, or it is the argument of a `return`
+/// with the name, which only counts if the word is still the `return` of the prelude.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tail { Root, Return(usize) }
+
+/// How a node is reached from the root of a chunk without passing through the arguments of a call,
+/// that is where the tree walker looks for tail calls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reach { Statement, Tail(Tail) }
+
+/// Where an operation came from: the node, the head of a call and the positions of its arguments,
+/// which are needed for the same errors and backtraces the tree walker produces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub node: Node,
+    pub head: Node,
+    pub poses: Vec<Position>
+}
+
+/// The compiled form of a node tree. Executing it leaves the value of the node on the stack.
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub ops: Vec<Op>,
+    pub consts: Vec<V>,
+    pub names: Vec<String>,
+    pub closures: Vec<Rc<ClosureNode>>,
+    pub sites: Vec<Site>,
+    /// The positions of the nodes compiled since the last `Fuel`. Their steps are taken together
+    /// before the next operation that has an effect, which is when the tree walker would have taken them all.
+    pending: Vec<Position>
+}
+impl Chunk {
+    pub fn compile(node: &Node) -> Self {
+        let mut chunk = Self::default();
+        chunk.node(node, Some(Reach::Tail(Tail::Root)));
+        chunk.fuel();
+        chunk
+    }
+    fn op(&mut self, op: Op) { self.ops.push(op) }
+    fn fuel(&mut self) {
+        if self.pending.is_empty() { return }
+        let poses = std::mem::take(&mut self.pending);
+        self.sites.push(Site { node: Node(N::Null, poses[0].clone()), head: Node(N::Null, poses[0].clone()), poses });
+        self.op(Op::Fuel(self.sites.len() - 1));
+    }
+    fn constant(&mut self, value: V) {
+        self.consts.push(value);
+        self.op(Op::Const(self.consts.len() - 1));
+    }
+    fn name(&mut self, word: &String) -> usize {
+        match self.names.iter().position(|name| name == word) {
+            Some(idx) => idx,
+            None => { self.names.push(word.clone()); self.names.len() - 1 }
+        }
+    }
+    fn site(&mut self, node: &Node, head: Option<&Node>, poses: Vec<Position>) -> usize {
+        let head = match head {
+            Some(Node(N::Word(word) | N::Prelude(word, _) | N::Local(word, ..), pos)) => Node(N::Word(word.clone()), pos.clone()),
+            Some(Node(_, pos)) => Node(N::Null, pos.clone()),
+            None => Node(N::Null, node.1.clone())
+        };
+        self.sites.push(Site { node: Node(N::Null, node.1.clone()), head, poses });
+        self.sites.len() - 1
+    }
+    /// Compiles `node` so that a signal raised in it is kept as its value.
+    fn catch(&mut self, node: &Node) {
+        let idx = self.ops.len();
+        self.op(Op::Catch(0));
+        self.node(node, None);
+        self.fuel();
+        self.op(Op::Uncatch);
+        self.ops[idx] = Op::Catch(self.ops.len());
+    }
+    fn node(&mut self, node: &Node, reach: Option<Reach>) {
+        self.pending.push(node.1.clone());
+        match &node.0 {
+            N::Null => self.constant(V::Null),
+            N::Wirldcard => self.constant(V::Wirldcard),
+            N::Int(v) => self.constant(V::Int(*v)),
+            N::Float(v) => self.constant(V::Float(*v)),
+            N::Bool(v) => self.constant(V::Bool(*v)),
+            N::String(v) => self.constant(V::String(v.clone())),
+            N::Type(v) => self.constant(V::Type(v.clone())),
+            N::Addr(n) => {
+                if let N::Word(addr) = &n.0 {
+                    let name = self.name(addr);
+                    return self.op(Op::Name(name))
+                }
+                self.catch(n);
+                let site = self.site(node, None, vec![]);
+                self.fuel();
+                self.op(Op::Addr(site));
+            }
+            N::Arg(n) => {
+                if let N::Int(idx) = n.0 { return self.op(Op::Arg(idx)) }
+                self.catch(n);
+                let site = self.site(node, None, vec![]);
+                self.fuel();
+                self.op(Op::DynArg(site));
+            }
+            N::Closure(n) => {
+                self.closures.push(n.clone());
+                self.op(Op::Closure(self.closures.len() - 1));
+            }
+            N::Pattern(nodes) => {
+                for n in nodes {
+                    self.catch(n);
+                    let site = self.site(n, None, vec![]);
+                    self.fuel();
+                    self.op(Op::IsType(site));
+                }
+                self.op(Op::Pattern(nodes.len()));
+            }
+            N::Word(word) => {
+                let name = self.name(word);
+                self.op(Op::Word(name));
+            }
+            N::Prelude(word, slot) => {
+                let name = self.name(word);
+                self.op(Op::Prelude(name, *slot));
+            }
+            N::Local(word, depth, slot) => {
+                let name = self.name(word);
+                self.op(Op::Local(name, *depth, *slot));
+            }
+            N::Eval(nodes) => {
+                let Some((head, args)) = nodes.split_first() else { return self.constant(V::Null) };
+                let word = if let N::Word(word) | N::Prelude(word, _) | N::Local(word, ..) = &head.0 { Some(word) } else { None };
+                match word {
+                    Some(word) if reach.is_some() && word == "return" && args.len() == 1 => {
+                        let name = self.name(word);
+                        self.node(&args[0], Some(Reach::Tail(Tail::Return(name))));
+                    }
+                    _ => for n in args { self.node(n, None); }
+                }
+                self.node(head, None);
+                let site = self.site(node, Some(head), args.iter().map(|n| n.1.clone()).collect());
+                let tail = if let Some(Reach::Tail(tail)) = reach { Some(tail) } else { None };
+                self.fuel();
+                match (reach, tail) {
+                    (Some(_), _) if word.is_some_and(|word| word == "if") => self.op(Op::If(site, tail)),
+                    (_, Some(tail)) => self.op(Op::TailCall(site, tail)),
+                    _ => self.op(Op::Call(site))
+                }
+            }
+            N::Body(nodes) => {
+                for n in nodes {
+                    self.node(n, reach.map(|_| Reach::Statement));
+                    self.op(Op::Pop);
+                }
+                self.constant(V::Null);
+            }
+            N::Map(entries) => {
+                for (k, v) in entries {
+                    self.node(k, None);
+                    self.node(v, None);
+                    let site = self.site(k, None, vec![]);
+                    self.fuel();
+                    self.op(Op::IsKey(site));
+                }
+                self.op(Op::Map(entries.len()));
+            }
+            N::Vector(nodes) => {
+                for n in nodes { self.node(n, None); }
+                self.op(Op::Vector(nodes.len()));
+            }
+        }
+    }
+}
+
+/// Compiles `node` and runs it, like [`crate::evaluator::get`] does.
+pub fn get(node: &Node, context: &mut Context) -> Result<(V, R), E> {
+    exec(&Chunk::compile(node), context)
+}
+/// The chunk of the node of a closure, which is only compiled the first time.
+fn chunk(node: &ClosureNode) -> &Chunk {
+    node.chunk.get_or_init(|| Chunk::compile(&node.node))
+}
+/// Runs the node of a closure inline.
+pub fn closure(node: &ClosureNode, context: &mut Context) -> Result<(V, R), E> {
+    exec(chunk(node), context)
+}
+/// Runs the node of a called closure, leaving a call in tail position to the caller, see [`crate::evaluator::eval`].
+pub(crate) fn call(node: &ClosureNode, context: &mut Context) -> Result<Step, E> {
+    run(chunk(node), true, true, context)
+}
+
+pub fn exec(chunk: &Chunk, context: &mut Context) -> Result<(V, R), E> {
+    match run(chunk, false, false, context)? {
+        Step::Done(value, ret) => Ok((value, ret)),
+        Step::Call(_) => unreachable!()
+    }
+}
+/// Runs `chunk`. In a called closure (`called`) calls in tail position are not made but returned,
+/// `position` tells whether the value of the chunk is the value of the closure.
+fn run(chunk: &Chunk, called: bool, position: bool, context: &mut Context) -> Result<Step, E> {
+    let mut stack: Vec<V> = vec![];
+    // the targets of the open `Catch`es with the stack height to return to
+    let mut catches: Vec<(usize, usize)> = vec![];
+    let mut ip = 0;
+    while let Some(op) = chunk.ops.get(ip) {
+        ip += 1;
+        match op {
+            Op::Fuel(site) => context.burn(&chunk.sites[*site].poses)?,
+            Op::Const(idx) => stack.push(chunk.consts[*idx].clone()),
+            Op::Word(idx) => stack.push(word(&chunk.names[*idx], context)),
+            Op::Prelude(_, slot) => stack.push(context.get_prelude(*slot).unwrap_or(V::Null)),
+            Op::Local(idx, depth, slot) => stack.push(context.get_local(&chunk.names[*idx], *depth, *slot).unwrap_or(V::Null)),
+            Op::Name(idx) => stack.push(V::Addr(chunk.names[*idx].clone(), Some(context.env()))),
+            Op::Arg(idx) => stack.push(context.arg(*idx).unwrap_or(V::Null)),
+            Op::DynArg(site) => {
+                let value = Type::Int.cast(&stack.pop().unwrap());
+                let V::Int(idx) = value else {
+                    context.trace(&chunk.sites[*site].node.1);
+                    return Err(E::ExpectedType{ typ: Type::String, recv_typ: value.typ() })
+                };
+                stack.push(context.arg(idx).unwrap_or(V::Null));
+            }
+            Op::Addr(site) => {
+                let value = Type::String.cast(&stack.pop().unwrap());
+                let V::String(addr) = value else {
+                    context.trace(&chunk.sites[*site].node.1);
+                    return Err(E::ExpectedType{ typ: Type::String, recv_typ: value.typ() })
+                };
+                stack.push(V::Addr(addr, Some(context.env())));
+            }
+            Op::Closure(idx) => stack.push(V::Closure(chunk.closures[*idx].clone(), context.path.clone(), context.env())),
+            Op::IsType(site) => {
+                let value = stack.last().unwrap();
+                if !matches!(value, V::Type(_)) {
+                    context.trace(&chunk.sites[*site].node.1);
+                    return Err(E::ExpectedType { typ: Type::Type, recv_typ: value.typ() })
+                }
+            }
+            Op::IsKey(site) => {
+                let key = &stack[stack.len() - 2];
+                if key.key().is_none() {
+                    context.trace(&chunk.sites[*site].node.1);
+                    return Err(E::ExpectedType { typ: Type::key(), recv_typ: key.typ() })
+                }
+            }
+            Op::Pattern(len) => {
+                let types = stack.split_off(stack.len() - len).into_iter()
+                    .map(|value| if let V::Type(typ) = value { typ } else { unreachable!() }).collect();
+                stack.push(V::Pattern(types));
+            }
+            Op::Vector(len) => {
+                let values = stack.split_off(stack.len() - len);
+                stack.push(V::vector(values));
+            }
+            Op::Map(len) => {
+                let mut values = stack.split_off(stack.len() - 2 * len).into_iter();
+                let mut map = BTreeMap::new();
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    map.insert(key.key().unwrap(), value);
+                }
+                stack.push(V::Map(map));
+            }
+            Op::Call(site) | Op::TailCall(site, _) | Op::If(site, _) => {
+                let site = &chunk.sites[*site];
+                let head = stack.pop().unwrap();
+                let args = stack.split_off(stack.len() - site.poses.len());
+                let types: Vec<Type> = args.iter().map(|v| v.typ()).collect();
+                let poses: Vec<&Position> = site.poses.iter().collect();
+                let tail = match op {
+                    Op::TailCall(_, tail) | Op::If(_, Some(tail)) if called => match tail {
+                        Tail::Root => position,
+                        Tail::Return(name) => prelude_native(&word(&chunk.names[*name], context), &chunk.names[*name], context)
+                    }
+                    _ => false
+                };
+                let returns = tail && matches!(op, Op::TailCall(_, Tail::Return(_)) | Op::If(_, Some(Tail::Return(_))));
+                let step = match (op, &head, &site.head.0) {
+                    (Op::If(..), V::NativFunction(params, _), N::Word(word)) if called && prelude_native(&head, word, context) => {
+                        check(params, &types, &poses, &site.head, &site.node, context)?;
+                        match branch(&args) {
+                            V::Closure(n, closure_path, env) => {
+                                let chunk = self::chunk(n);
+                                inside(env, closure_path, None, context, |context| run(chunk, true, tail, context))?
+                            }
+                            value => Step::Done(value.clone(), R::None)
+                        }
+                    }
+                    (_, V::Closure(..) | V::Function(..), _) if tail => {
+                        let call = Call {
+                            head_value: head, head: site.head.clone(), args, types, poses: site.poses.clone(), node: site.node.clone(),
+                            env: context.env(), path: context.path.clone(), returns: false
+                        };
+                        Step::Call(Box::new(call))
+                    }
+                    _ => {
+                        let (value, ret) = eval(head, &site.head, args, types, poses, &site.node, context)?;
+                        Step::Done(value, ret)
+                    }
+                };
+                let (value, ret) = match step {
+                    Step::Done(value, ret) => (value, ret),
+                    Step::Call(mut call) => {
+                        call.returns |= returns;
+                        return Ok(Step::Call(call))
+                    }
+                };
+                if ret == R::None {
+                    stack.push(value);
+                    continue
+                }
+                let Some((target, height)) = catches.pop() else { return Ok(Step::Done(value, ret)) };
+                stack.truncate(height);
+                stack.push(value);
+                ip = target;
+            }
+            Op::Pop => { stack.pop(); }
+            Op::Catch(target) => catches.push((*target, stack.len())),
+            Op::Uncatch => { catches.pop(); }
+        }
+    }
+    Ok(Step::Done(stack.pop().unwrap_or(V::Null), R::None))
+}